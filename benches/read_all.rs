@@ -0,0 +1,66 @@
+// Copyright 2016, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compare `read_all` with reading the four attributes by path
+//!
+//! The attributes live in a fake sysfs tree below the temp directory, so
+//! this measures the syscall overhead rather than a driver.  Run with
+//! `cargo bench --bench read_all`.
+
+extern crate sysfs_pwm;
+
+use std::fs;
+use std::hint::black_box;
+use std::time::Instant;
+
+use sysfs_pwm::pwm::Pwm;
+
+const ITERATIONS: u32 = 20_000;
+const ATTRS: [&str; 4] = ["period", "duty_cycle", "polarity", "enable"];
+
+fn bench<F: FnMut()>(name: &str, mut f: F) {
+    f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let per_iter = start.elapsed() / ITERATIONS;
+    println!("{:<24} {:>8?}/iter", name, per_iter);
+}
+
+fn main() {
+    let root =
+        std::env::temp_dir().join(format!("sysfs-pwm-bench-read-all-{}", std::process::id()));
+    let pin = root.join("pwmchip0/pwm0");
+    fs::create_dir_all(&pin).unwrap();
+    fs::write(root.join("pwmchip0/npwm"), "1\n").unwrap();
+    for (attr, value) in ATTRS.iter().zip(["20000\n", "5000\n", "normal\n", "1\n"]) {
+        fs::write(pin.join(attr), value).unwrap();
+    }
+
+    let pwm = Pwm::with_root(&root, 0, 0)
+        .unwrap()
+        .into_exported()
+        .unwrap();
+    bench("read_all", || {
+        black_box(pwm.read_all().unwrap());
+    });
+    bench("individual getters", || {
+        black_box(pwm.get_period_ns().unwrap());
+        black_box(pwm.get_duty_cycle_ns().unwrap());
+        black_box(pwm.get_polarity().unwrap());
+        black_box(pwm.get_enabled().unwrap());
+    });
+    bench("read_to_string by path", || {
+        for attr in ATTRS {
+            black_box(fs::read_to_string(pin.join(attr)).unwrap());
+        }
+    });
+
+    fs::remove_dir_all(root).unwrap();
+}
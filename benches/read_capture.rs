@@ -0,0 +1,90 @@
+// Copyright 2016, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compare the allocations of `read_capture_into` and `get_capture`
+//!
+//! A counting global allocator records every allocation made while
+//! polling the capture of a fake sysfs tree below the temp directory.  Run
+//! with `cargo bench --bench read_capture`.
+
+extern crate sysfs_pwm;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use sysfs_pwm::pwm::Pwm;
+
+const ITERATIONS: u32 = 20_000;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn bench<F: FnMut()>(name: &str, mut f: F) {
+    f();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let per_iter = start.elapsed() / ITERATIONS;
+    let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - allocations) as f64;
+    println!(
+        "{:<20} {:>8?}/iter {:>6.2} allocations/iter",
+        name,
+        per_iter,
+        allocations / f64::from(ITERATIONS)
+    );
+}
+
+fn main() {
+    let root = std::env::temp_dir().join(format!(
+        "sysfs-pwm-bench-read-capture-{}",
+        std::process::id()
+    ));
+    let pin = root.join("pwmchip0/pwm0");
+    fs::create_dir_all(&pin).unwrap();
+    fs::write(root.join("pwmchip0/npwm"), "1\n").unwrap();
+    fs::write(pin.join("capture"), "20000 5000\n").unwrap();
+
+    let pwm = Pwm::with_root(&root, 0, 0)
+        .unwrap()
+        .into_exported()
+        .unwrap();
+    bench("get_capture", || {
+        black_box(pwm.get_capture().unwrap());
+    });
+    let mut buf = String::new();
+    bench("read_capture_into", || {
+        black_box(pwm.read_capture_into(&mut buf).unwrap());
+    });
+
+    fs::remove_dir_all(root).unwrap();
+}
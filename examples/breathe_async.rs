@@ -12,22 +12,22 @@
 extern crate sysfs_pwm;
 extern crate tokio;
 use sysfs_pwm::common::Result;
-use sysfs_pwm::pwm_async::PwmAsync;
+use sysfs_pwm::pwm_async::{ExportedPwmAsync, PwmAsync};
 
 // PIN: EHRPWM0A (P9_22)
 const BB_PWM_CHIP: u32 = 0;
 const BB_PWM_NUMBER: u32 = 0;
 
 async fn pwm_increase_to_max(
-    pwm: &PwmAsync,
+    pwm: &ExportedPwmAsync,
     duration_ms: u32,
     update_period_ms: u32,
 ) -> Result<()> {
     let step: f32 = duration_ms as f32 / update_period_ms as f32;
     let mut duty_cycle = 0.0;
-    let period_ns: u32 = pwm.get_period_ns().await?;
+    let period_ns: u64 = pwm.get_period_ns().await?;
     while duty_cycle < 1.0 {
-        pwm.set_duty_cycle_ns((duty_cycle * period_ns as f32) as u32)
+        pwm.set_duty_cycle_ns((duty_cycle * period_ns as f32) as u64)
             .await?;
         duty_cycle += step;
     }
@@ -35,15 +35,15 @@ async fn pwm_increase_to_max(
 }
 
 async fn pwm_decrease_to_minimum(
-    pwm: &PwmAsync,
+    pwm: &ExportedPwmAsync,
     duration_ms: u32,
     update_period_ms: u32,
 ) -> Result<()> {
     let step: f32 = duration_ms as f32 / update_period_ms as f32;
     let mut duty_cycle = 1.0;
-    let period_ns: u32 = pwm.get_period_ns().await?;
+    let period_ns: u64 = pwm.get_period_ns().await?;
     while duty_cycle > 0.0 {
-        pwm.set_duty_cycle_ns((duty_cycle * period_ns as f32) as u32)
+        pwm.set_duty_cycle_ns((duty_cycle * period_ns as f32) as u64)
             .await?;
         duty_cycle -= step;
     }
@@ -56,12 +56,12 @@ async fn pwm_decrease_to_minimum(
 async fn main() {
     let pwm_async = PwmAsync::new(BB_PWM_CHIP, BB_PWM_NUMBER).await.unwrap(); // number depends on chip, etc.
     pwm_async
-        .with_exported(|| async {
-            pwm_async.enable(true).await.unwrap();
-            pwm_async.set_period_ns(20_000).await.unwrap();
+        .with_exported(async |pwm| {
+            pwm.enable(true).await.unwrap();
+            pwm.set_period_ns(20_000).await.unwrap();
             loop {
-                pwm_increase_to_max(&pwm_async, 1000, 20).await.unwrap();
-                pwm_decrease_to_minimum(&pwm_async, 1000, 20).await.unwrap();
+                pwm_increase_to_max(pwm, 1000, 20).await.unwrap();
+                pwm_decrease_to_minimum(pwm, 1000, 20).await.unwrap();
             }
         })
         .await
@@ -0,0 +1,30 @@
+// Copyright 2016, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+extern crate sysfs_pwm;
+use std::thread;
+use std::time::Duration;
+use sysfs_pwm::pwm::Pwm;
+
+// PIN: EHRPWM0A (P9_22)
+const BB_PWM_CHIP: u32 = 0;
+const BB_PWM_NUMBER: u32 = 0;
+
+/// Arm an ESC, ramp the motor up to half throttle and back down again
+fn main() {
+    let pwm = Pwm::new(BB_PWM_CHIP, BB_PWM_NUMBER).unwrap(); // number depends on chip, etc.
+    pwm.with_exported(|pwm| {
+        pwm.arm_esc()?;
+        for step in (0..=50).chain((0..50).rev()) {
+            pwm.set_throttle(step as f32 / 100.0)?;
+            thread::sleep(Duration::from_millis(50));
+        }
+        pwm.set_throttle(0.0)
+    })
+    .unwrap();
+}
@@ -9,22 +9,72 @@
 // Portions of this implementation are based on work by Nat Pryce:
 // https://github.com/npryce/rusty-pi/blob/master/src/pi/gpio.rs
 
+use std::collections::HashMap;
+#[cfg(any(test, feature = "test-util"))]
+use std::collections::HashSet;
 use std::convert;
+use std::ffi::{CStr, CString};
 use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
 use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+/// Errors returned by this crate
+///
+/// New variants may be added in minor releases, so code matching on this
+/// type must include a wildcard arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
-    /// Simple IO error
-    Io(io::Error),
+    /// IO error while accessing the given path
+    ///
+    /// The path is empty if the error did not originate from a sysfs file.
+    Io { path: PathBuf, source: io::Error },
     /// Read unusual data from sysfs file.
     Unexpected(String),
+    /// The pin could not be exported because it is already in use
+    PinBusy { chip: u32, pin: u32 },
+    /// A duty cycle was written before the period was configured
+    PeriodNotSet,
+    /// A polling operation gave up before its condition was met
+    ///
+    /// `operation` describes what was being waited for, e.g. "waiting for
+    /// enable to become 1", and `waited` is how long was spent polling.
+    Timeout { operation: String, waited: Duration },
+    /// A value does not fit in the range accepted by sysfs
+    OutOfRange(String),
+    /// Access to the given sysfs file was denied
+    ///
+    /// Sysfs PWM attributes are usually only writable by root unless a
+    /// udev rule grants access.  `source` is the EACCES or EPERM error,
+    /// which an io error mapper sees before it is turned into this.
+    PermissionDenied { path: PathBuf, source: io::Error },
+    /// Resetting some of the exported pins of a chip failed
+    ///
+    /// `PwmChip::reset_all_exported` carries on past a pin which fails, and
+    /// `failures` holds the pin number and error of every such pin.
+    ResetFailed {
+        chip: u32,
+        failures: Vec<(u32, Error)>,
+    },
 }
 
 impl ::std::error::Error for Error {
-    fn cause(&self) -> Option<&dyn ::std::error::Error> {
+    fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
         match *self {
-            Error::Io(ref e) => Some(e),
+            Error::Io { ref source, .. } | Error::PermissionDenied { ref source, .. } => {
+                Some(source)
+            }
+            Error::ResetFailed { ref failures, .. } => failures
+                .first()
+                .map(|(_, e)| e as &(dyn ::std::error::Error + 'static)),
             _ => None,
         }
     }
@@ -33,22 +83,1813 @@ impl ::std::error::Error for Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Error::Io(ref e) => e.fmt(f),
+            Error::Io {
+                ref path,
+                ref source,
+            } => {
+                if path.as_os_str().is_empty() {
+                    source.fmt(f)
+                } else {
+                    write!(f, "failed to access {}: {}", path.display(), source)
+                }
+            }
             Error::Unexpected(ref s) => write!(f, "Unexpected: {}", s),
+            Error::PinBusy { chip, pin } => write!(
+                f,
+                "pwmchip{}/pwm{} is busy; it may be claimed by a kernel consumer \
+                 such as pwm-backlight, check the device tree",
+                chip, pin
+            ),
+            Error::PeriodNotSet => write!(
+                f,
+                "The period is 0; set the period before setting the duty cycle"
+            ),
+            Error::Timeout {
+                ref operation,
+                waited,
+            } => write!(f, "Timed out {} after {:?}", operation, waited),
+            Error::OutOfRange(ref s) => write!(f, "Out of range: {}", s),
+            Error::PermissionDenied { ref path, .. } => write!(
+                f,
+                "permission denied accessing {}; run as root or add a udev rule \
+                 granting access",
+                path.display()
+            ),
+            Error::ResetFailed { chip, ref failures } => {
+                write!(f, "failed to reset pwmchip{}:", chip)?;
+                for (i, (pin, e)) in failures.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { ";" };
+                    write!(f, "{} pwm{}: {}", sep, pin, e)?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
+impl Error {
+    /// Build a mapper attaching `path` to an io error
+    ///
+    /// EACCES and EPERM become `Error::PermissionDenied`.
+    pub(crate) fn io<P: Into<PathBuf>>(path: P) -> impl FnOnce(io::Error) -> Error {
+        move |source| {
+            if source.kind() == io::ErrorKind::PermissionDenied {
+                Error::PermissionDenied {
+                    path: path.into(),
+                    source,
+                }
+            } else {
+                Error::Io {
+                    path: path.into(),
+                    source,
+                }
+            }
+        }
+    }
+
+    /// Map an io error from writing to the `export` file at `path`,
+    /// recognizing EBUSY
+    pub(crate) fn from_export(e: io::Error, path: PathBuf, chip: u32, pin: u32) -> Error {
+        if e.kind() == io::ErrorKind::ResourceBusy {
+            Error::PinBusy { chip, pin }
+        } else {
+            Error::io(path)(e)
+        }
+    }
+}
+
+/// Allows conversions which cannot fail wherever fallible ones are accepted
+impl convert::From<convert::Infallible> for Error {
+    fn from(never: convert::Infallible) -> Error {
+        match never {}
+    }
+}
+
 impl convert::From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
-        Error::Io(e)
+        Error::Io {
+            path: PathBuf::new(),
+            source: e,
+        }
     }
 }
 
-#[derive(Debug)]
+#[cfg(feature = "embedded-hal")]
+impl embedded_hal::pwm::Error for Error {
+    fn kind(&self) -> embedded_hal::pwm::ErrorKind {
+        embedded_hal::pwm::ErrorKind::Other
+    }
+}
+
+/// The polarity of a PWM signal, `Normal` by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Polarity {
+    #[default]
     Normal,
     Inverse,
 }
 
+impl Polarity {
+    /// The spelling of this polarity written to the sysfs attribute
+    pub fn to_sysfs(&self) -> &'static str {
+        match *self {
+            Polarity::Normal => "normal",
+            Polarity::Inverse => "inversed",
+        }
+    }
+
+    /// Parse the contents of the sysfs polarity attribute
+    ///
+    /// "inverse", "inversed" and "inverted" are all accepted since drivers
+    /// disagree on the spelling.
+    pub fn try_from_sysfs(s: &str) -> Result<Polarity> {
+        match trim_value(s) {
+            "normal" => Ok(Polarity::Normal),
+            "inverse" | "inversed" | "inverted" => Ok(Polarity::Inverse),
+            _ => Err(Error::Unexpected(format!(
+                "Unexpected polarity file contents: {:?}",
+                s
+            ))),
+        }
+    }
+
+    /// The opposite polarity
+    pub fn inverse(self) -> Polarity {
+        match self {
+            Polarity::Normal => Polarity::Inverse,
+            Polarity::Inverse => Polarity::Normal,
+        }
+    }
+}
+
+/// Formats the polarity as written to sysfs, "normal" or "inversed"
+impl fmt::Display for Polarity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.to_sysfs())
+    }
+}
+
+/// Parses any spelling accepted by `Polarity::try_from_sysfs`
+impl FromStr for Polarity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Polarity> {
+        Polarity::try_from_sysfs(s)
+    }
+}
+
+/// The configuration of a PWM as read from sysfs
+///
+/// With the `serde` feature this can be persisted and later written back
+/// with `restore`.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PwmState {
+    pub period_ns: u64,
+    pub duty_cycle_ns: u64,
+    pub polarity: Polarity,
+    pub enabled: bool,
+}
+
+impl PwmState {
+    /// The duty cycle as a fraction of the period, or 0.0 if the period is
+    /// 0
+    pub fn duty_fraction(&self) -> f64 {
+        match self.period_ns {
+            0 => 0.0,
+            period_ns => self.duty_cycle_ns as f64 / period_ns as f64,
+        }
+    }
+
+    /// Whether the output is high at `phase_ns` into the period
+    ///
+    /// With normal polarity the output is high for the first
+    /// `duty_cycle_ns` of each period, with inverse polarity it is low
+    /// then.  Phases beyond the period wrap around.  `enabled` is not taken
+    /// into account since the level of a disabled output depends on the
+    /// driver.
+    pub fn level_at(&self, phase_ns: u64) -> bool {
+        let active = match self.period_ns {
+            0 => false,
+            period_ns => phase_ns % period_ns < self.duty_cycle_ns,
+        };
+        match self.polarity {
+            Polarity::Normal => active,
+            Polarity::Inverse => !active,
+        }
+    }
+}
+
+/// Build a state from `(period_ns, duty_cycle_ns, polarity, enabled)`
+impl convert::From<(u64, u64, Polarity, bool)> for PwmState {
+    fn from((period_ns, duty_cycle_ns, polarity, enabled): (u64, u64, Polarity, bool)) -> PwmState {
+        PwmState {
+            period_ns,
+            duty_cycle_ns,
+            polarity,
+            enabled,
+        }
+    }
+}
+
+impl convert::From<PwmState> for (u64, u64, Polarity, bool) {
+    fn from(state: PwmState) -> (u64, u64, Polarity, bool) {
+        (
+            state.period_ns,
+            state.duty_cycle_ns,
+            state.polarity,
+            state.enabled,
+        )
+    }
+}
+
+/// A complete PWM configuration to be written with `ExportedPwm::apply`
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PwmConfig {
+    pub period_ns: u64,
+    pub duty_cycle_ns: u64,
+    pub polarity: Polarity,
+    pub enabled: bool,
+}
+
+impl PwmConfig {
+    /// Start building a configuration
+    ///
+    /// Unset fields default to a zero period and duty cycle, normal
+    /// polarity and a disabled output.
+    pub fn builder() -> PwmConfigBuilder {
+        PwmConfigBuilder {
+            config: PwmConfig {
+                period_ns: 0,
+                duty_cycle_ns: 0,
+                polarity: Polarity::Normal,
+                enabled: false,
+            },
+        }
+    }
+}
+
+/// Fluent construction of a `PwmConfig`, created by `PwmConfig::builder`
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct PwmConfigBuilder {
+    config: PwmConfig,
+}
+
+impl PwmConfigBuilder {
+    /// Set the period in nanoseconds
+    pub fn period_ns(mut self, period_ns: u64) -> Self {
+        self.config.period_ns = period_ns;
+        self
+    }
+
+    /// Set the duty cycle in nanoseconds
+    pub fn duty_cycle_ns(mut self, duty_cycle_ns: u64) -> Self {
+        self.config.duty_cycle_ns = duty_cycle_ns;
+        self
+    }
+
+    /// Set the polarity
+    pub fn polarity(mut self, polarity: Polarity) -> Self {
+        self.config.polarity = polarity;
+        self
+    }
+
+    /// Enable or disable the output
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.config.enabled = enabled;
+        self
+    }
+
+    /// Finish building the configuration
+    pub fn build(self) -> PwmConfig {
+        self.config
+    }
+}
+
+/// An exact duty cycle expressed as `num / den` of the period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DutyRatio {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl DutyRatio {
+    /// The duty cycle in nanoseconds for the given period, rounded down
+    pub(crate) fn duty_cycle_ns(&self, period_ns: u64) -> Result<u64> {
+        if self.den == 0 || self.num > self.den {
+            return Err(Error::OutOfRange(format!(
+                "Invalid duty ratio {}/{}",
+                self.num, self.den
+            )));
+        }
+        // num <= den, so the result never exceeds period_ns
+        Ok((u128::from(period_ns) * u128::from(self.num) / u128::from(self.den)) as u64)
+    }
+
+    /// The ratio `duty_cycle_ns / period_ns` reduced to lowest terms
+    ///
+    /// Returns `Error::OutOfRange` if the reduced terms do not fit in `u32`.
+    pub(crate) fn reduced(duty_cycle_ns: u64, period_ns: u64) -> Result<DutyRatio> {
+        let (mut a, mut b) = (duty_cycle_ns, period_ns);
+        while b != 0 {
+            let t = a % b;
+            a = b;
+            b = t;
+        }
+        match (
+            u32::try_from(duty_cycle_ns / a),
+            u32::try_from(period_ns / a),
+        ) {
+            (Ok(num), Ok(den)) => Ok(DutyRatio { num, den }),
+            _ => Err(Error::OutOfRange(format!(
+                "{}/{} cannot be reduced to a 32-bit ratio",
+                duty_cycle_ns, period_ns
+            ))),
+        }
+    }
+}
+
+/// A single measurement read from the capture attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capture {
+    pub period_ns: u64,
+    pub duty_cycle_ns: u64,
+}
+
+impl Capture {
+    /// The measured frequency in Hz, or 0.0 if no period was measured
+    pub fn frequency_hz(&self) -> f64 {
+        match self.period_ns {
+            0 => 0.0,
+            period_ns => 1_000_000_000.0 / period_ns as f64,
+        }
+    }
+
+    /// The measured duty cycle as a fraction of the period, or 0.0 if no
+    /// period was measured
+    pub fn duty_fraction(&self) -> f64 {
+        match self.period_ns {
+            0 => 0.0,
+            period_ns => self.duty_cycle_ns as f64 / period_ns as f64,
+        }
+    }
+}
+
+/// Build a capture from `(period_ns, duty_cycle_ns)`
+impl convert::From<(u64, u64)> for Capture {
+    fn from((period_ns, duty_cycle_ns): (u64, u64)) -> Capture {
+        Capture {
+            period_ns,
+            duty_cycle_ns,
+        }
+    }
+}
+
+impl convert::From<Capture> for (u64, u64) {
+    fn from(capture: Capture) -> (u64, u64) {
+        (capture.period_ns, capture.duty_cycle_ns)
+    }
+}
+
+/// A frequency, always positive and finite
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Frequency(f64);
+
+impl Frequency {
+    /// A frequency of `hz` Hz
+    ///
+    /// Returns an error unless `hz` is positive and finite.
+    pub fn from_hz(hz: f64) -> Result<Frequency> {
+        if !hz.is_finite() || hz <= 0.0 {
+            return Err(Error::OutOfRange(format!(
+                "Frequency must be positive and finite, got {} Hz",
+                hz
+            )));
+        }
+        Ok(Frequency(hz))
+    }
+
+    /// A frequency of `khz` kHz
+    pub fn from_khz(khz: f64) -> Result<Frequency> {
+        Frequency::from_hz(khz * 1_000.0)
+    }
+
+    /// The frequency in Hz
+    pub fn as_hz(self) -> f64 {
+        self.0
+    }
+
+    /// The frequency in kHz
+    pub fn as_khz(self) -> f64 {
+        self.0 / 1_000.0
+    }
+}
+
+/// Validates that `hz` is positive and finite, see `Frequency::from_hz`
+impl convert::TryFrom<f64> for Frequency {
+    type Error = Error;
+
+    fn try_from(hz: f64) -> Result<Frequency> {
+        Frequency::from_hz(hz)
+    }
+}
+
+/// A duty cycle as a fraction of the period, always within 0.0..=1.0
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct DutyCycle(f64);
+
+impl DutyCycle {
+    /// A duty cycle of `fraction` of the period
+    ///
+    /// Values outside 0.0..=1.0 are clamped; NaN is rejected.
+    pub fn from_fraction(fraction: f64) -> Result<DutyCycle> {
+        if fraction.is_nan() {
+            return Err(Error::OutOfRange("Duty cycle must not be NaN".to_string()));
+        }
+        Ok(DutyCycle(fraction.clamp(0.0, 1.0)))
+    }
+
+    /// A duty cycle of `percent` percent of the period
+    ///
+    /// Values outside 0..=100 are clamped; NaN is rejected.
+    pub fn from_percent(percent: f64) -> Result<DutyCycle> {
+        DutyCycle::from_fraction(percent / 100.0)
+    }
+
+    /// The duty cycle as a fraction of the period
+    pub fn as_fraction(self) -> f64 {
+        self.0
+    }
+
+    /// The duty cycle in percent of the period
+    pub fn as_percent(self) -> f64 {
+        self.0 * 100.0
+    }
+
+    /// The duty cycle in nanoseconds for the given period
+    pub(crate) fn duty_cycle_ns(self, period_ns: u64) -> Result<u64> {
+        fraction_of_period(period_ns, self.0)
+    }
+}
+
+/// Spacing of the steps taken by a frequency sweep
+#[derive(Debug)]
+pub enum SweepScale {
+    Linear,
+    Logarithmic,
+}
+
+impl SweepScale {
+    /// Frequency of step `step` out of `steps` between `from_hz` and `to_hz`
+    pub(crate) fn frequency(&self, from_hz: f64, to_hz: f64, step: usize, steps: usize) -> f64 {
+        if steps < 2 {
+            return from_hz;
+        }
+        let t = step as f64 / (steps - 1) as f64;
+        match *self {
+            SweepScale::Linear => from_hz + (to_hz - from_hz) * t,
+            SweepScale::Logarithmic => from_hz * (to_hz / from_hz).powf(t),
+        }
+    }
+}
+
 pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Where the kernel exposes the PWM class in sysfs
+pub(crate) const SYSFS_PWM_ROOT: &str = "/sys/class/pwm";
+
+/// The path of attribute `name` of `pwm{pin}` on `pwmchip{chip}` below `root`
+#[cfg(any(feature = "tokio", feature = "async-std", test, feature = "test-util"))]
+#[inline]
+pub(crate) fn attr_path(root: &Path, chip: u32, pin: u32, name: &str) -> PathBuf {
+    root.join(format!("pwmchip{}/pwm{}/{}", chip, pin, name))
+}
+
+/// How often to re-read sysfs while waiting for a value to take effect
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Convert a `Duration` to nanoseconds of the width accepted by sysfs
+pub(crate) fn duration_to_ns(d: Duration) -> Result<u64> {
+    match u64::try_from(d.as_nanos()) {
+        Ok(ns) => Ok(ns),
+        Err(_) => Err(Error::OutOfRange(format!(
+            "{:?} does not fit in a sysfs PWM attribute",
+            d
+        ))),
+    }
+}
+
+/// Round a computed nanosecond value to the width accepted by sysfs
+///
+/// Unlike an `as` cast this rejects NaN, infinities and values outside
+/// the range of the attribute instead of saturating.
+pub(crate) fn f64_to_ns(ns: f64) -> Result<u64> {
+    let rounded = ns.round();
+    // u64::MAX as f64 rounds up to 2^64, which itself does not fit
+    if !rounded.is_finite() || rounded < 0.0 || rounded >= u64::MAX as f64 {
+        return Err(Error::OutOfRange(format!(
+            "{} ns does not fit in a sysfs PWM attribute",
+            ns
+        )));
+    }
+    Ok(rounded as u64)
+}
+
+/// The duty cycle in nanoseconds for `fraction` of `period_ns`
+///
+/// The result is clamped to the period, which float rounding of very long
+/// periods could otherwise exceed by a few nanoseconds.  A fraction of 1.0
+/// or more is the whole period even where that is not representable as an
+/// `f64`, e.g. `u64::MAX`.
+pub(crate) fn fraction_of_period(period_ns: u64, fraction: f64) -> Result<u64> {
+    if fraction >= 1.0 {
+        return Ok(period_ns);
+    }
+    Ok(f64_to_ns(period_ns as f64 * fraction)?.min(period_ns))
+}
+
+/// Trim the contents of a sysfs attribute for parsing
+///
+/// Besides surrounding whitespace this strips trailing NUL and other
+/// non-printable bytes, which some drivers append to their values.
+pub(crate) fn trim_value(s: &str) -> &str {
+    s.trim_end_matches(|c: char| c.is_whitespace() || c.is_control())
+        .trim_start()
+}
+
+/// Parse the contents of a numeric attribute
+pub(crate) fn parse_value<T: FromStr>(s: &str) -> Result<T> {
+    match trim_value(s).parse::<T>() {
+        Ok(r) => Ok(r),
+        Err(_) => Err(Error::Unexpected(format!(
+            "Unexpected value file contents: {:?}",
+            s
+        ))),
+    }
+}
+
+/// Parse the contents of a chip's `npwm` attribute
+pub(crate) fn parse_npwm(s: &str) -> Result<u32> {
+    match trim_value(s).parse::<u32>() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(Error::Unexpected(format!(
+            "Unexpected npwm contents: {:?}",
+            s
+        ))),
+    }
+}
+
+/// Return an error unless `pin` is below the `npwm` of pwmchip`chip`
+pub(crate) fn check_pin(chip: u32, npwm: u32, pin: u32) -> Result<()> {
+    if pin >= npwm {
+        return Err(Error::Unexpected(format!(
+            "pwmchip{} has {} channels, pin {} is out of range",
+            chip, npwm, pin
+        )));
+    }
+    Ok(())
+}
+
+/// `Ok` unless resetting a pin of `chip` failed, see `Error::ResetFailed`
+pub(crate) fn reset_result(chip: u32, failures: Vec<(u32, Error)>) -> Result<()> {
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::ResetFailed { chip, failures })
+    }
+}
+
+/// Return `Error::PeriodNotSet` if `period_ns` is 0
+pub(crate) fn check_period_set(period_ns: u64) -> Result<()> {
+    if period_ns == 0 {
+        return Err(Error::PeriodNotSet);
+    }
+    Ok(())
+}
+
+/// Return an error unless `duty_cycle_ns` may be written with `period_ns`
+///
+/// A duty cycle of 0 is always accepted; anything else needs a non-zero
+/// period it does not exceed.
+pub(crate) fn check_duty_cycle_ns(duty_cycle_ns: u64, period_ns: u64) -> Result<()> {
+    if duty_cycle_ns == 0 {
+        return Ok(());
+    }
+    check_period_set(period_ns)?;
+    if duty_cycle_ns > period_ns {
+        return Err(Error::OutOfRange(format!(
+            "duty cycle {} ns exceeds period {} ns",
+            duty_cycle_ns, period_ns
+        )));
+    }
+    Ok(())
+}
+
+/// The duty cycle as a fraction of the period, clamped to `0.0..=1.0`
+pub(crate) fn duty_fraction(duty_cycle_ns: u64, period_ns: u64) -> Result<f32> {
+    check_period_set(period_ns)?;
+    // divide in f64 so large periods keep their precision
+    let fraction = duty_cycle_ns as f64 / period_ns as f64;
+    Ok((fraction as f32).clamp(0.0, 1.0))
+}
+
+/// The fraction of the period during which the output is high
+pub(crate) fn high_fraction(duty_cycle_ns: u64, period_ns: u64, polarity: Polarity) -> Result<f64> {
+    check_period_set(period_ns)?;
+    let duty_cycle_ns = duty_cycle_ns.min(period_ns);
+    let high_ns = match polarity {
+        Polarity::Normal => duty_cycle_ns,
+        Polarity::Inverse => period_ns - duty_cycle_ns,
+    };
+    Ok(high_ns as f64 / period_ns as f64)
+}
+
+/// The duty cycle which keeps the output high for `on_ns` each period
+pub(crate) fn on_time_to_duty_ns(on_ns: u64, period_ns: u64, polarity: Polarity) -> Result<u64> {
+    check_period_set(period_ns)?;
+    check_duty_cycle_ns(on_ns, period_ns)?;
+    Ok(match polarity {
+        Polarity::Normal => on_ns,
+        Polarity::Inverse => period_ns - on_ns,
+    })
+}
+
+/// The frequency in Hz of a period in nanoseconds
+pub(crate) fn period_to_hz(period_ns: u64) -> Result<f64> {
+    check_period_set(period_ns)?;
+    Ok(1_000_000_000.0 / period_ns as f64)
+}
+
+/// Round a frequency to whole Hz
+///
+/// Returns `Error::OutOfRange` unless the result fits in `u32`.
+pub(crate) fn round_hz(hz: f64) -> Result<u32> {
+    let rounded = hz.round();
+    if !(0.0..=f64::from(u32::MAX)).contains(&rounded) {
+        return Err(Error::OutOfRange(format!(
+            "{} Hz does not fit in a u32",
+            hz
+        )));
+    }
+    Ok(rounded as u32)
+}
+
+/// The value to write to the `polarity` attribute
+///
+/// `inverse_spelling` replaces the default spelling of `Polarity::Inverse`.
+pub(crate) fn polarity_value(
+    polarity: Polarity,
+    inverse_spelling: Option<&'static str>,
+) -> &'static str {
+    match (polarity, inverse_spelling) {
+        (Polarity::Inverse, Some(spelling)) => spelling,
+        (polarity, _) => polarity.to_sysfs(),
+    }
+}
+
+/// Whether the result of reading a `capture` attribute shows that the
+/// driver supports capture
+///
+/// Only `ENOSYS`, which the kernel returns for drivers without a capture
+/// callback, means unsupported; any other failure comes from a capture
+/// that was actually attempted.
+pub(crate) fn capture_supported<T>(read: &Result<T>) -> bool {
+    !matches!(read, Err(Error::Io { source, .. }) if source.kind() == io::ErrorKind::Unsupported)
+}
+
+/// Parse the two integers of a `capture` attribute without allocating
+pub(crate) fn parse_capture(s: &str) -> Result<Capture> {
+    let mut values = trim_value(s).split_whitespace().map(str::parse::<u64>);
+    match (values.next(), values.next(), values.next()) {
+        (Some(Ok(period_ns)), Some(Ok(duty_cycle_ns)), None) => Ok(Capture {
+            period_ns,
+            duty_cycle_ns,
+        }),
+        _ => Err(Error::Unexpected(format!(
+            "Unexpected capture contents: {:?}",
+            s
+        ))),
+    }
+}
+
+/// Check that `duty_percent` is a finite percentage within 0.0..=100.0
+///
+/// This is checked before scaling to nanoseconds, where rounding would
+/// let values slightly outside the range through on short periods.
+pub(crate) fn check_duty_percent(duty_percent: f32) -> Result<()> {
+    if !(duty_percent.is_finite() && (0.0..=100.0).contains(&duty_percent)) {
+        return Err(Error::OutOfRange(format!(
+            "Duty cycle {}% is outside 0..=100%",
+            duty_percent
+        )));
+    }
+    Ok(())
+}
+
+/// Check that `duty_cycle` is a fraction of the period within 0.0..=1.0
+pub(crate) fn check_duty_fraction(duty_cycle: f32) -> Result<()> {
+    if duty_cycle.is_nan() {
+        return Err(Error::OutOfRange("Duty cycle must not be NaN".to_string()));
+    }
+    if !(0.0..=1.0).contains(&duty_cycle) {
+        return Err(Error::OutOfRange(format!(
+            "Duty cycle {} is outside 0.0..=1.0",
+            duty_cycle
+        )));
+    }
+    Ok(())
+}
+
+/// Parse the contents of an `enable` attribute
+///
+/// Besides the usual "1"/"0", the words "enabled"/"disabled" and "on"/"off"
+/// are accepted in any case since some drivers and simulated sysfs trees
+/// report those instead.
+pub(crate) fn parse_enabled(s: &str) -> Result<bool> {
+    match trim_value(s).to_ascii_lowercase().as_str() {
+        "1" | "enabled" | "on" => Ok(true),
+        "0" | "disabled" | "off" => Ok(false),
+        _ => Err(Error::Unexpected(format!(
+            "Unexpected enable file contents: {:?}",
+            s
+        ))),
+    }
+}
+
+/// Convert `value` in a unit of `ns_per_unit` nanoseconds to nanoseconds
+pub(crate) fn units_to_ns(value: u64, ns_per_unit: u64) -> Result<u64> {
+    match value.checked_mul(ns_per_unit) {
+        Some(ns) => Ok(ns),
+        None => Err(Error::OutOfRange(format!(
+            "{} * {} ns does not fit in a sysfs PWM attribute",
+            value, ns_per_unit
+        ))),
+    }
+}
+
+/// The whole period in nanoseconds nearest to a frequency of `target_hz`
+///
+/// Periods are clamped to at least 1 ns and saturate at `u64::MAX`.
+/// Returns 0 if `target_hz` is not a positive, finite number.
+pub fn period_ns_for_frequency(target_hz: f64) -> u64 {
+    if !target_hz.is_finite() || target_hz <= 0.0 {
+        return 0;
+    }
+    // float to int casts saturate
+    ((1_000_000_000.0 / target_hz).round() as u64).max(1)
+}
+
+/// The frequency closest to `target_hz` that an integer-nanosecond period
+/// can produce
+///
+/// Returns 0.0 if `target_hz` is not a positive, finite number.
+pub fn nearest_achievable_frequency(target_hz: f64) -> f64 {
+    match period_ns_for_frequency(target_hz) {
+        0 => 0.0,
+        period_ns => 1_000_000_000.0 / period_ns as f64,
+    }
+}
+
+/// Convert a frequency in Hz to the nearest whole period in nanoseconds
+///
+/// Frequencies whose period is shorter than 1 ns or too long for the
+/// attribute return `Error::OutOfRange` rather than saturating.
+pub(crate) fn hz_to_period_ns(hz: f64) -> Result<u64> {
+    if !hz.is_finite() || hz <= 0.0 {
+        return Err(Error::OutOfRange(format!(
+            "Frequency must be positive and finite, got {} Hz",
+            hz
+        )));
+    }
+    if (1_000_000_000.0 / hz).round() < 1.0 {
+        return Err(Error::OutOfRange(format!(
+            "{} Hz has a period shorter than 1 ns",
+            hz
+        )));
+    }
+    f64_to_ns(1_000_000_000.0 / hz)
+}
+
+/// The embedded-hal maximum duty cycle for a period: the period in
+/// nanoseconds, capped to `u16::MAX` and never 0
+#[cfg(feature = "embedded-hal")]
+pub(crate) fn hal_max_duty_cycle(period_ns: u64) -> u16 {
+    period_ns.clamp(1, u16::MAX as u64) as u16
+}
+
+/// Scale an embedded-hal duty cycle into nanoseconds of `period_ns`
+#[cfg(feature = "embedded-hal")]
+pub(crate) fn hal_duty_cycle_ns(period_ns: u64, duty: u16) -> Result<u64> {
+    check_period_set(period_ns)?;
+    let max = period_ns.min(u16::MAX as u64);
+    let duty = (duty as u64).min(max);
+    Ok((period_ns as u128 * duty as u128 / max as u128) as u64)
+}
+
+/// Round a nanosecond value to the nearest multiple of `resolution_ns`
+pub(crate) fn align_ns(ns: u128, resolution_ns: u64) -> Result<u64> {
+    if resolution_ns == 0 {
+        return Err(Error::OutOfRange("Resolution must be non-zero".to_string()));
+    }
+    let resolution_ns = u128::from(resolution_ns);
+    let aligned = (ns + resolution_ns / 2) / resolution_ns * resolution_ns;
+    if aligned > u128::from(u64::MAX) {
+        return Err(Error::OutOfRange(format!(
+            "{} ns does not fit in a sysfs PWM attribute",
+            aligned
+        )));
+    }
+    Ok(aligned as u64)
+}
+
+/// How long `arm_esc` holds the minimum throttle pulse
+///
+/// Most hobby ESCs arm after seeing the minimum pulse for one to two
+/// seconds.
+pub const ESC_ARM_DURATION: Duration = Duration::from_secs(2);
+
+/// The 50 Hz period expected by RC ESCs and servos
+pub(crate) const ESC_PERIOD_NS: u64 = 20_000_000;
+/// The pulse width for zero throttle
+pub(crate) const ESC_MIN_PULSE_NS: u64 = 1_000_000;
+/// The pulse width for full throttle
+pub(crate) const ESC_MAX_PULSE_NS: u64 = 2_000_000;
+
+/// Map a throttle in 0.0..=1.0 onto the ESC pulse width
+pub(crate) fn throttle_to_pulse_ns(throttle: f32) -> Result<u64> {
+    if !(0.0..=1.0).contains(&throttle) {
+        return Err(Error::OutOfRange(format!(
+            "Throttle must be between 0.0 and 1.0, got {}",
+            throttle
+        )));
+    }
+    let span = (ESC_MAX_PULSE_NS - ESC_MIN_PULSE_NS) as f64;
+    Ok(ESC_MIN_PULSE_NS + f64_to_ns(span * f64::from(throttle))?)
+}
+
+/// A user-supplied mapping from I/O errors to errors of this crate
+///
+/// See `Pwm::set_io_error_mapper`.
+#[derive(Clone)]
+pub(crate) struct IoErrorMapper(Arc<IoErrorMapperFn>);
+
+type IoErrorMapperFn = dyn Fn(&io::Error) -> Option<Error> + Send + Sync;
+
+impl fmt::Debug for IoErrorMapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("IoErrorMapper")
+    }
+}
+
+impl IoErrorMapper {
+    pub(crate) fn new<F>(mapper: F) -> IoErrorMapper
+    where
+        F: Fn(&io::Error) -> Option<Error> + Send + Sync + 'static,
+    {
+        IoErrorMapper(Arc::new(mapper))
+    }
+
+    /// Replace an I/O error with the mapped error, if the mapper gives one
+    ///
+    /// The mapper sees the raw `io::Error` of both `Error::Io` and
+    /// `Error::PermissionDenied`; the error is only kept as it is when the
+    /// mapper returns `None`.
+    pub(crate) fn map(&self, err: Error) -> Error {
+        match err {
+            Error::Io { ref source, .. } | Error::PermissionDenied { ref source, .. } => {
+                (self.0)(source).unwrap_or(err)
+            }
+            err => err,
+        }
+    }
+}
+
+/// Values returned by reads on a dry-run Pwm before anything is written
+///
+/// Dry-run mode is intended for testing and local development only.
+#[derive(Debug)]
+pub struct DryRunDefaults {
+    pub period_ns: u64,
+    pub duty_cycle_ns: u64,
+    pub polarity: Polarity,
+    pub enabled: bool,
+    pub capture: (u64, u64),
+}
+
+impl Default for DryRunDefaults {
+    fn default() -> DryRunDefaults {
+        DryRunDefaults {
+            period_ns: 0,
+            duty_cycle_ns: 0,
+            polarity: Polarity::Normal,
+            enabled: false,
+            capture: (0, 0),
+        }
+    }
+}
+
+/// Storage for the attributes of PWM pins
+///
+/// Every Pwm sends its attribute accesses as well as export and unexport
+/// through a backend: a `SysfsBackend` for `Pwm::new` and `Pwm::with_root`,
+/// or the one given to `Pwm::with_backend`, so downstream code can be run
+/// against a fake and the exact writes inspected.  `chip` and `pin`
+/// identify `pwmchip{chip}/pwm{pin}`; `attr` is the attribute name such as
+/// "period".  Backends are called synchronously, also from `PwmAsync`.
+pub trait PwmBackend: fmt::Debug + Send + Sync {
+    /// Read the contents of an attribute
+    fn read(&self, chip: u32, pin: u32, attr: &str) -> Result<String>;
+
+    /// Write `value` to an attribute
+    fn write(&self, chip: u32, pin: u32, attr: &str, value: &[u8]) -> Result<()>;
+
+    /// Read an attribute into `buf`, replacing its contents
+    ///
+    /// By default this copies the result of `read`; backends which can
+    /// read in place override it to save the allocation.
+    fn read_into(&self, chip: u32, pin: u32, attr: &str, buf: &mut String) -> Result<()> {
+        let value = self.read(chip, pin, attr)?;
+        buf.clear();
+        buf.push_str(&value);
+        Ok(())
+    }
+
+    /// Open an attribute for repeated writes
+    ///
+    /// Returns the path and an open file which callers rewind and rewrite,
+    /// e.g. `DutyCycleWriter`.  Backends without files return `Ok(None)`,
+    /// the default, and every value then goes through `write`.
+    fn open_write(&self, chip: u32, pin: u32, attr: &str) -> Result<Option<(PathBuf, File)>> {
+        let _ = (chip, pin, attr);
+        Ok(None)
+    }
+
+    /// Read an attribute of the chip itself, such as "npwm"
+    ///
+    /// Fails by default.
+    fn read_chip(&self, chip: u32, attr: &str) -> Result<String> {
+        Err(Error::Unexpected(format!(
+            "Backend has no pwmchip{}/{} attribute",
+            chip, attr
+        )))
+    }
+
+    /// Export a pin; does nothing by default
+    fn export(&self, chip: u32, pin: u32) -> Result<()> {
+        let _ = (chip, pin);
+        Ok(())
+    }
+
+    /// Unexport a pin; does nothing by default
+    fn unexport(&self, chip: u32, pin: u32) -> Result<()> {
+        let _ = (chip, pin);
+        Ok(())
+    }
+
+    /// Whether a pin is exported; always true by default
+    fn is_exported(&self, chip: u32, pin: u32) -> Result<bool> {
+        let _ = (chip, pin);
+        Ok(true)
+    }
+
+    /// Numbers of the exported pins of a chip, sorted
+    ///
+    /// By default every channel below the chip's `npwm` is checked with
+    /// `is_exported`.
+    fn exported(&self, chip: u32) -> Result<Vec<u32>> {
+        let npwm = parse_npwm(&self.read_chip(chip, "npwm")?)?;
+        let mut pins = Vec::new();
+        for pin in 0..npwm {
+            if self.is_exported(chip, pin)? {
+                pins.push(pin);
+            }
+        }
+        Ok(pins)
+    }
+
+    /// The sysfs directory accessed by this backend, if any
+    ///
+    /// Chips are equal if their backends have the same root; backends
+    /// without one, the default, are only equal to themselves.
+    fn sysfs_root(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Whether two backends access the same pins, see `PwmBackend::sysfs_root`
+pub(crate) fn same_backend(a: &Arc<dyn PwmBackend>, b: &Arc<dyn PwmBackend>) -> bool {
+    match (a.sysfs_root(), b.sysfs_root()) {
+        (Some(a), Some(b)) => a == b,
+        (None, None) => Arc::ptr_eq(a, b),
+        _ => false,
+    }
+}
+
+/// Call `f` with `name` as a C string
+///
+/// Attribute names are short, so they are copied to the stack rather than
+/// allocating a `CString` for every open.
+fn with_c_name<T, F>(name: &str, f: F) -> io::Result<T>
+where
+    F: FnOnce(&CStr) -> T,
+{
+    let mut buf = [0u8; 64];
+    if name.len() < buf.len() {
+        buf[..name.len()].copy_from_slice(name.as_bytes());
+        let name = CStr::from_bytes_with_nul(&buf[..=name.len()])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(f(name))
+    } else {
+        let name =
+            CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(f(&name))
+    }
+}
+
+/// A sysfs directory held open so its attributes can be accessed relative
+/// to it
+///
+/// Attributes are opened with `openat` against the directory fd instead of
+/// by absolute path.  This saves the path walk on every access and ensures
+/// the attribute belongs to the directory originally opened, even if that
+/// directory is removed and recreated in the meantime.
+#[derive(Debug)]
+pub(crate) struct AttrDir {
+    pub(crate) path: PathBuf,
+    dir: File,
+}
+
+impl AttrDir {
+    pub(crate) fn open<P: Into<PathBuf>>(path: P) -> Result<AttrDir> {
+        let path = path.into();
+        let dir = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY | libc::O_CLOEXEC)
+            .open(&path)
+            .map_err(Error::io(&path))?;
+        Ok(AttrDir { path, dir })
+    }
+
+    pub(crate) fn open_dir(&self, name: &str) -> Result<AttrDir> {
+        let path = self.path.join(name);
+        let dir = self
+            .openat(name, libc::O_RDONLY | libc::O_DIRECTORY)
+            .map_err(Error::io(&path))?;
+        Ok(AttrDir { path, dir })
+    }
+
+    fn openat(&self, name: &str, flags: libc::c_int) -> io::Result<File> {
+        let fd = with_c_name(name, |name| {
+            // SAFETY: `name` is a valid C string and the fd is owned by `self.dir`
+            unsafe { libc::openat(self.dir.as_raw_fd(), name.as_ptr(), flags | libc::O_CLOEXEC) }
+        })?;
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: openat returned a new fd which nothing else owns
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    pub(crate) fn exists(&self, name: &str) -> bool {
+        with_c_name(name, |name| {
+            // SAFETY: `name` is a valid C string and the fd is owned by `self.dir`
+            unsafe { libc::faccessat(self.dir.as_raw_fd(), name.as_ptr(), libc::F_OK, 0) == 0 }
+        })
+        .unwrap_or(false)
+    }
+
+    pub(crate) fn open_write(&self, name: &str) -> Result<File> {
+        self.openat(name, libc::O_WRONLY | libc::O_TRUNC)
+            .map_err(Error::io(self.path.join(name)))
+    }
+
+    pub(crate) fn read(&self, name: &str) -> Result<String> {
+        let mut s = String::new();
+        self.read_into(name, &mut s)?;
+        Ok(s)
+    }
+
+    /// Read `name` into `buf`, replacing its contents
+    pub(crate) fn read_into(&self, name: &str, buf: &mut String) -> Result<()> {
+        buf.clear();
+        self.openat(name, libc::O_RDONLY)
+            .and_then(|mut f| f.read_to_string(buf))
+            .map(|_| ())
+            .map_err(|e| Error::io(self.path.join(name))(e))
+    }
+
+    pub(crate) fn write(&self, name: &str, value: &[u8]) -> Result<()> {
+        self.openat(name, libc::O_WRONLY | libc::O_TRUNC)
+            .and_then(|mut f| f.write_all(value))
+            .map_err(Error::io(self.path.join(name)))
+    }
+}
+
+/// A backend accessing the sysfs files below a root directory
+///
+/// This is the backend of every Pwm created with `Pwm::new` or
+/// `Pwm::with_root`.  Chip and pin directories are opened once and
+/// attributes are opened relative to them, see `AttrDir`.  A pin's
+/// directory is reopened after it is exported or unexported through the
+/// backend.  Clones share the open directories.
+#[derive(Debug, Clone)]
+pub struct SysfsBackend {
+    root: PathBuf,
+    dirs: Arc<Mutex<SysfsDirs>>,
+}
+
+/// The directories held open by a `SysfsBackend`
+#[derive(Debug, Default)]
+struct SysfsDirs {
+    chips: HashMap<u32, Arc<AttrDir>>,
+    pins: HashMap<(u32, u32), Arc<AttrDir>>,
+}
+
+impl SysfsBackend {
+    /// A backend for `/sys/class/pwm`
+    pub fn new() -> SysfsBackend {
+        SysfsBackend::with_root(SYSFS_PWM_ROOT)
+    }
+
+    /// A backend for the chips below `root`
+    pub fn with_root<P: Into<PathBuf>>(root: P) -> SysfsBackend {
+        SysfsBackend {
+            root: root.into(),
+            dirs: Arc::default(),
+        }
+    }
+
+    /// The directory below which the chips are found
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The `pwmchipN` directory, opened on first use
+    pub(crate) fn chip_dir(&self, chip: u32) -> Result<Arc<AttrDir>> {
+        let mut dirs = self.dirs.lock().unwrap();
+        if let Some(dir) = dirs.chips.get(&chip) {
+            return Ok(dir.clone());
+        }
+        let dir = Arc::new(AttrDir::open(self.root.join(format!("pwmchip{}", chip)))?);
+        dirs.chips.insert(chip, dir.clone());
+        Ok(dir)
+    }
+
+    /// The `pwmN` directory of a chip, opened on first use
+    fn pin_dir(&self, chip: u32, pin: u32) -> Result<Arc<AttrDir>> {
+        let chip_dir = self.chip_dir(chip)?;
+        let mut dirs = self.dirs.lock().unwrap();
+        if let Some(dir) = dirs.pins.get(&(chip, pin)) {
+            return Ok(dir.clone());
+        }
+        let dir = Arc::new(chip_dir.open_dir(&format!("pwm{}", pin))?);
+        dirs.pins.insert((chip, pin), dir.clone());
+        Ok(dir)
+    }
+
+    /// Close the `pwmN` directory so it is reopened on the next access
+    fn forget_pin(&self, chip: u32, pin: u32) {
+        self.dirs.lock().unwrap().pins.remove(&(chip, pin));
+    }
+}
+
+impl Default for SysfsBackend {
+    fn default() -> SysfsBackend {
+        SysfsBackend::new()
+    }
+}
+
+impl PwmBackend for SysfsBackend {
+    fn read(&self, chip: u32, pin: u32, attr: &str) -> Result<String> {
+        self.pin_dir(chip, pin)?.read(attr)
+    }
+
+    fn write(&self, chip: u32, pin: u32, attr: &str, value: &[u8]) -> Result<()> {
+        self.pin_dir(chip, pin)?.write(attr, value)
+    }
+
+    fn read_into(&self, chip: u32, pin: u32, attr: &str, buf: &mut String) -> Result<()> {
+        self.pin_dir(chip, pin)?.read_into(attr, buf)
+    }
+
+    fn open_write(&self, chip: u32, pin: u32, attr: &str) -> Result<Option<(PathBuf, File)>> {
+        let dir = self.pin_dir(chip, pin)?;
+        Ok(Some((dir.path.join(attr), dir.open_write(attr)?)))
+    }
+
+    fn read_chip(&self, chip: u32, attr: &str) -> Result<String> {
+        self.chip_dir(chip)?.read(attr)
+    }
+
+    /// Nothing is written if the pin is already exported, and pins beyond
+    /// the chip's `npwm` are rejected
+    fn export(&self, chip: u32, pin: u32) -> Result<()> {
+        let dir = self.chip_dir(chip)?;
+        self.forget_pin(chip, pin);
+        if dir.exists(&format!("pwm{}", pin)) {
+            return Ok(());
+        }
+        check_pin(chip, parse_npwm(&dir.read("npwm")?)?, pin)?;
+        dir.open_write("export")?
+            .write_all(pin.to_string().as_bytes())
+            .map_err(|e| Error::from_export(e, dir.path.join("export"), chip, pin))
+    }
+
+    fn unexport(&self, chip: u32, pin: u32) -> Result<()> {
+        let dir = self.chip_dir(chip)?;
+        self.forget_pin(chip, pin);
+        if !dir.exists(&format!("pwm{}", pin)) {
+            return Ok(());
+        }
+        dir.write("unexport", pin.to_string().as_bytes())
+    }
+
+    fn is_exported(&self, chip: u32, pin: u32) -> Result<bool> {
+        Ok(self.chip_dir(chip)?.exists(&format!("pwm{}", pin)))
+    }
+
+    /// The `pwmN` entries of the chip directory
+    fn exported(&self, chip: u32) -> Result<Vec<u32>> {
+        let dir = self.chip_dir(chip)?;
+        let mut numbers = Vec::new();
+        for entry in fs::read_dir(&dir.path).map_err(Error::io(&dir.path))? {
+            let name = entry.map_err(Error::io(&dir.path))?.file_name();
+            if let Some(number) = name.to_str().and_then(|n| n.strip_prefix("pwm")) {
+                if let Ok(number) = number.parse::<u32>() {
+                    numbers.push(number);
+                }
+            }
+        }
+        numbers.sort_unstable();
+        Ok(numbers)
+    }
+
+    fn sysfs_root(&self) -> Option<&Path> {
+        Some(self.root())
+    }
+}
+
+/// An in-memory backend for tests, available with the `test-util` feature
+///
+/// Chips exist once their channel count is set with `set_npwm`.  Exporting
+/// a pin creates its `period`, `duty_cycle`, `enable` and `polarity`
+/// attributes with kernel-like defaults unless they were seeded with `set`,
+/// and unexporting removes them again.  Accessing a pin which is not
+/// exported fails with a not-found `Error::Io` like sysfs does.  Every
+/// attribute write is recorded in order and can be inspected with `writes`.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    state: Mutex<MemoryState>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Default)]
+struct MemoryState {
+    npwm: HashMap<u32, u32>,
+    exported: HashSet<(u32, u32)>,
+    values: HashMap<(u32, u32, String), String>,
+    writes: Vec<(u32, u32, String, String)>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MemoryBackend {
+    /// An empty backend without any chips
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    /// Create chip `chip` with `npwm` channels
+    pub fn set_npwm(&self, chip: u32, npwm: u32) {
+        self.state.lock().unwrap().npwm.insert(chip, npwm);
+    }
+
+    /// Seed an attribute value without recording a write
+    pub fn set(&self, chip: u32, pin: u32, attr: &str, value: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .values
+            .insert((chip, pin, attr.to_string()), value.to_string());
+    }
+
+    /// The current value of an attribute, if any
+    pub fn get(&self, chip: u32, pin: u32, attr: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.values.get(&(chip, pin, attr.to_string())).cloned()
+    }
+
+    /// Every attribute write so far as `(chip, pin, attr, value)`, in order
+    pub fn writes(&self) -> Vec<(u32, u32, String, String)> {
+        self.state.lock().unwrap().writes.clone()
+    }
+
+    /// Forget the recorded writes
+    pub fn clear_writes(&self) {
+        self.state.lock().unwrap().writes.clear();
+    }
+
+    fn not_found(chip: u32, pin: u32, attr: &str) -> Error {
+        Error::Io {
+            path: attr_path(Path::new(""), chip, pin, attr),
+            source: io::Error::new(io::ErrorKind::NotFound, "memory attribute not found"),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl PwmBackend for MemoryBackend {
+    /// Only "npwm" exists, for chips created with `set_npwm`
+    fn read_chip(&self, chip: u32, attr: &str) -> Result<String> {
+        match (attr, self.state.lock().unwrap().npwm.get(&chip)) {
+            ("npwm", Some(npwm)) => Ok(npwm.to_string()),
+            _ => Err(Error::Io {
+                path: PathBuf::from(format!("pwmchip{}/{}", chip, attr)),
+                source: io::Error::new(io::ErrorKind::NotFound, "memory attribute not found"),
+            }),
+        }
+    }
+
+    fn read(&self, chip: u32, pin: u32, attr: &str) -> Result<String> {
+        let state = self.state.lock().unwrap();
+        if !state.exported.contains(&(chip, pin)) {
+            return Err(MemoryBackend::not_found(chip, pin, attr));
+        }
+        match state.values.get(&(chip, pin, attr.to_string())) {
+            Some(value) => Ok(value.clone()),
+            None => Err(MemoryBackend::not_found(chip, pin, attr)),
+        }
+    }
+
+    fn write(&self, chip: u32, pin: u32, attr: &str, value: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.exported.contains(&(chip, pin)) {
+            return Err(MemoryBackend::not_found(chip, pin, attr));
+        }
+        let value = String::from_utf8_lossy(value).into_owned();
+        state
+            .values
+            .insert((chip, pin, attr.to_string()), value.clone());
+        state.writes.push((chip, pin, attr.to_string(), value));
+        Ok(())
+    }
+
+    fn export(&self, chip: u32, pin: u32) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match state.npwm.get(&chip) {
+            Some(&npwm) => check_pin(chip, npwm, pin)?,
+            None => {
+                return Err(Error::Io {
+                    path: PathBuf::from(format!("pwmchip{}/export", chip)),
+                    source: io::Error::new(io::ErrorKind::NotFound, "memory chip not found"),
+                })
+            }
+        }
+        if state.exported.insert((chip, pin)) {
+            for (attr, value) in [
+                ("period", "0"),
+                ("duty_cycle", "0"),
+                ("enable", "0"),
+                ("polarity", "normal"),
+            ] {
+                state
+                    .values
+                    .entry((chip, pin, attr.to_string()))
+                    .or_insert_with(|| value.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn unexport(&self, chip: u32, pin: u32) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.exported.remove(&(chip, pin)) {
+            state.values.retain(|&(c, p, _), _| (c, p) != (chip, pin));
+        }
+        Ok(())
+    }
+
+    fn is_exported(&self, chip: u32, pin: u32) -> Result<bool> {
+        Ok(self.state.lock().unwrap().exported.contains(&(chip, pin)))
+    }
+}
+
+/// In-memory attribute store backing a dry-run Pwm
+///
+/// Writes are logged, to stderr unless another logger is given, and
+/// remembered so that subsequent reads observe them.
+pub(crate) struct DryRun {
+    values: Mutex<HashMap<String, String>>,
+    logger: Box<DryRunLoggerFn>,
+}
+
+type DryRunLoggerFn = dyn Fn(&str) + Send + Sync;
+
+impl fmt::Debug for DryRun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DryRun")
+            .field("values", &self.values)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DryRun {
+    pub(crate) fn new(defaults: DryRunDefaults) -> DryRun {
+        DryRun::with_logger(defaults, |line| eprintln!("{}", line))
+    }
+
+    pub(crate) fn with_logger<F>(defaults: DryRunDefaults, logger: F) -> DryRun
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        let mut values = HashMap::new();
+        values.insert("period".to_string(), defaults.period_ns.to_string());
+        values.insert("duty_cycle".to_string(), defaults.duty_cycle_ns.to_string());
+        values.insert(
+            "polarity".to_string(),
+            defaults.polarity.to_sysfs().to_string(),
+        );
+        values.insert("enable".to_string(), (defaults.enabled as u8).to_string());
+        values.insert(
+            "capture".to_string(),
+            format!("{} {}", defaults.capture.0, defaults.capture.1),
+        );
+        DryRun {
+            values: Mutex::new(values),
+            logger: Box::new(logger),
+        }
+    }
+}
+
+impl PwmBackend for DryRun {
+    fn read(&self, _chip: u32, _pin: u32, name: &str) -> Result<String> {
+        match self.values.lock().unwrap().get(name) {
+            Some(v) => Ok(v.clone()),
+            None => Err(Error::Io {
+                path: PathBuf::from(name),
+                source: io::Error::new(io::ErrorKind::NotFound, "dry-run attribute not found"),
+            }),
+        }
+    }
+
+    fn write(&self, chip: u32, pin: u32, name: &str, value: &[u8]) -> Result<()> {
+        let value = String::from_utf8_lossy(value).into_owned();
+        (self.logger)(&format!(
+            "sysfs-pwm dry-run: pwmchip{chip}/pwm{pin}/{name} <- {value:?}"
+        ));
+        self.values.lock().unwrap().insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn export(&self, chip: u32, pin: u32) -> Result<()> {
+        (self.logger)(&format!("sysfs-pwm dry-run: pwmchip{chip} export pwm{pin}"));
+        Ok(())
+    }
+
+    fn unexport(&self, chip: u32, pin: u32) -> Result<()> {
+        (self.logger)(&format!(
+            "sysfs-pwm dry-run: pwmchip{chip} unexport pwm{pin}"
+        ));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fraction_of_period_near_u64_max() {
+        assert_eq!(fraction_of_period(u64::MAX, 1.0).unwrap(), u64::MAX);
+        assert_eq!(fraction_of_period(u64::MAX, 0.0).unwrap(), 0);
+        let half = fraction_of_period(u64::MAX, 0.5).unwrap();
+        assert!(half.abs_diff(u64::MAX / 2) <= 1 << 11);
+        let almost = fraction_of_period(u64::MAX - 1, 1.0 - f64::EPSILON).unwrap();
+        assert!(almost < u64::MAX - 1);
+        assert_eq!(
+            DutyCycle::from_fraction(1.0)
+                .unwrap()
+                .duty_cycle_ns(u64::MAX)
+                .unwrap(),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn fraction_of_period_tiny_periods() {
+        assert_eq!(fraction_of_period(1, 1.0).unwrap(), 1);
+        assert_eq!(fraction_of_period(1, 0.4).unwrap(), 0);
+        assert_eq!(fraction_of_period(1, 0.5).unwrap(), 1);
+        assert_eq!(fraction_of_period(3, 1.0 / 3.0).unwrap(), 1);
+        assert_eq!(fraction_of_period(0, 0.5).unwrap(), 0);
+    }
+
+    #[test]
+    fn f64_to_ns_rejects_unrepresentable() {
+        assert!(matches!(
+            f64_to_ns(u64::MAX as f64),
+            Err(Error::OutOfRange(_))
+        ));
+        assert!(matches!(f64_to_ns(-1.0), Err(Error::OutOfRange(_))));
+        assert!(matches!(f64_to_ns(f64::NAN), Err(Error::OutOfRange(_))));
+        assert!(matches!(
+            f64_to_ns(f64::INFINITY),
+            Err(Error::OutOfRange(_))
+        ));
+        assert_eq!(f64_to_ns(-0.4).unwrap(), 0);
+        assert_eq!(f64_to_ns(2.5).unwrap(), 3);
+        // the largest f64 below 2^64 still fits
+        assert_eq!(
+            f64_to_ns(18_446_744_073_709_549_568.0).unwrap(),
+            u64::MAX - 2047
+        );
+    }
+
+    #[test]
+    fn hz_to_period_ns_extremes() {
+        assert_eq!(hz_to_period_ns(0.01).unwrap(), 100_000_000_000);
+        assert_eq!(hz_to_period_ns(1e9).unwrap(), 1);
+        assert_eq!(hz_to_period_ns(2e9).unwrap(), 1);
+        assert!(matches!(hz_to_period_ns(3e9), Err(Error::OutOfRange(_))));
+        assert!(matches!(hz_to_period_ns(1e-12), Err(Error::OutOfRange(_))));
+        assert!(matches!(hz_to_period_ns(0.0), Err(Error::OutOfRange(_))));
+        assert!(matches!(
+            hz_to_period_ns(f64::NAN),
+            Err(Error::OutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn round_hz_checks_the_range() {
+        assert_eq!(round_hz(49.99998).unwrap(), 50);
+        assert_eq!(round_hz(1e9).unwrap(), 1_000_000_000);
+        assert_eq!(round_hz(f64::from(u32::MAX)).unwrap(), u32::MAX);
+        for hz in [5e9, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(round_hz(hz), Err(Error::OutOfRange(_))));
+        }
+    }
+
+    #[test]
+    fn invalid_inputs_are_out_of_range() {
+        let ratio = DutyRatio { num: 2, den: 1 };
+        assert!(matches!(
+            ratio.duty_cycle_ns(1_000),
+            Err(Error::OutOfRange(_))
+        ));
+        let ratio = DutyRatio { num: 0, den: 0 };
+        assert!(matches!(
+            ratio.duty_cycle_ns(1_000),
+            Err(Error::OutOfRange(_))
+        ));
+        assert!(matches!(align_ns(1_000, 0), Err(Error::OutOfRange(_))));
+    }
+
+    #[test]
+    fn duty_fraction_extremes() {
+        assert_eq!(duty_fraction(u64::MAX, u64::MAX).unwrap(), 1.0);
+        assert_eq!(duty_fraction(0, u64::MAX).unwrap(), 0.0);
+        assert!(duty_fraction(1, u64::MAX).unwrap() > 0.0);
+        assert_eq!(duty_fraction(2, 1).unwrap(), 1.0);
+        assert!(matches!(duty_fraction(1, 0), Err(Error::PeriodNotSet)));
+    }
+
+    #[test]
+    fn period_ns_for_frequency_edges() {
+        assert_eq!(period_ns_for_frequency(1.0), 1_000_000_000);
+        assert_eq!(period_ns_for_frequency(1_000_000.0), 1_000);
+        assert_eq!(nearest_achievable_frequency(1.0), 1.0);
+        assert_eq!(nearest_achievable_frequency(1_000_000.0), 1_000_000.0);
+        assert_eq!(hz_to_period_ns(1.0).unwrap(), 1_000_000_000);
+        assert_eq!(hz_to_period_ns(1_000_000.0).unwrap(), 1_000);
+        assert_eq!(period_to_hz(1_000_000_000).unwrap(), 1.0);
+        assert_eq!(period_to_hz(1_000).unwrap(), 1_000_000.0);
+    }
+
+    #[test]
+    fn period_ns_for_frequency_rounds_to_nearest() {
+        // 3 MHz is 333.33 ns, which rounds down
+        assert_eq!(period_ns_for_frequency(3_000_000.0), 333);
+        assert_eq!(nearest_achievable_frequency(3_000_000.0), 1e9 / 333.0);
+        // 1.5 MHz is 666.67 ns, which rounds up
+        assert_eq!(period_ns_for_frequency(1_500_000.0), 667);
+        // halfway rounds away from zero: 400 MHz is 2.5 ns
+        assert_eq!(period_ns_for_frequency(400_000_000.0), 3);
+        // never below 1 ns, and 0 for invalid input
+        assert_eq!(period_ns_for_frequency(1e12), 1);
+        assert_eq!(period_ns_for_frequency(0.0), 0);
+        assert_eq!(period_ns_for_frequency(-1.0), 0);
+        assert_eq!(nearest_achievable_frequency(f64::NAN), 0.0);
+    }
+
+    #[test]
+    fn parse_value_strips_trailing_nul() {
+        assert_eq!(parse_value::<u64>("1000\0\n").unwrap(), 1000);
+        assert_eq!(parse_value::<u64>("1000\n\0").unwrap(), 1000);
+        assert_eq!(parse_value::<u64>(" 1000\0\0").unwrap(), 1000);
+        assert_eq!(parse_npwm("2\0\n").unwrap(), 2);
+        assert!(parse_enabled("1\0\n").unwrap());
+        assert_eq!(trim_value("normal\0\n"), "normal");
+        assert!(parse_value::<u64>("10\x0000").is_err());
+    }
+
+    #[test]
+    fn polarity_sysfs_spellings() {
+        for s in ["normal", "normal\n", " normal\0"] {
+            assert_eq!(Polarity::try_from_sysfs(s).unwrap(), Polarity::Normal);
+        }
+        for s in [
+            "inverse",
+            "inversed",
+            "inverted",
+            "inversed\n",
+            "inverted\0\n",
+        ] {
+            assert_eq!(Polarity::try_from_sysfs(s).unwrap(), Polarity::Inverse);
+        }
+        for s in ["", "Normal", "inv", "reversed"] {
+            assert!(Polarity::try_from_sysfs(s).is_err());
+        }
+        assert_eq!(Polarity::Normal.to_sysfs(), "normal");
+        assert_eq!(Polarity::Inverse.to_sysfs(), "inversed");
+        for polarity in [Polarity::Normal, Polarity::Inverse] {
+            assert_eq!(
+                Polarity::try_from_sysfs(polarity.to_sysfs()).unwrap(),
+                polarity
+            );
+            assert_eq!(polarity.to_string().parse::<Polarity>().unwrap(), polarity);
+        }
+    }
+
+    #[test]
+    fn pwm_state_tuple_round_trip() {
+        let tuple = (20_000_000, 1_500_000, Polarity::Inverse, true);
+        let state = PwmState::from(tuple);
+        assert_eq!(state.period_ns, 20_000_000);
+        assert_eq!(state.duty_cycle_ns, 1_500_000);
+        assert_eq!(state.polarity, Polarity::Inverse);
+        assert!(state.enabled);
+        assert_eq!(<(u64, u64, Polarity, bool)>::from(state), tuple);
+    }
+
+    #[test]
+    fn level_at_normal_polarity() {
+        let state = PwmState::from((1_000, 250, Polarity::Normal, true));
+        assert!(state.level_at(0));
+        assert!(state.level_at(125));
+        assert!(state.level_at(249));
+        assert!(!state.level_at(250));
+        assert!(!state.level_at(999));
+        // the end of one period is the start of the next
+        assert!(state.level_at(1_000));
+    }
+
+    #[test]
+    fn level_at_inverse_polarity() {
+        let state = PwmState::from((1_000, 250, Polarity::Inverse, true));
+        assert!(!state.level_at(0));
+        assert!(!state.level_at(125));
+        assert!(!state.level_at(249));
+        assert!(state.level_at(250));
+        assert!(state.level_at(999));
+        assert!(!state.level_at(1_000));
+    }
+
+    #[test]
+    fn level_at_edge_duty_cycles() {
+        let off = PwmState::from((1_000, 0, Polarity::Normal, true));
+        let full = PwmState::from((1_000, 1_000, Polarity::Normal, true));
+        let unset = PwmState::from((0, 0, Polarity::Inverse, false));
+        for phase in [0, 500, 999] {
+            assert!(!off.level_at(phase));
+            assert!(full.level_at(phase));
+            assert!(unset.level_at(phase));
+        }
+    }
+
+    /// A deterministic xorshift sequence for the property tests below
+    fn samples(mut state: u64) -> impl Iterator<Item = u64> {
+        std::iter::repeat_with(move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        })
+    }
+
+    /// Interesting floats followed by pseudo-random ones of every magnitude
+    fn float_samples() -> impl Iterator<Item = f64> {
+        let edges = [
+            0.0,
+            -0.0,
+            1.0,
+            -1.0,
+            0.5,
+            100.0,
+            f64::MIN_POSITIVE,
+            f64::EPSILON,
+            f64::MAX,
+            f64::MIN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::NAN,
+        ];
+        edges
+            .into_iter()
+            .chain(
+                samples(0x9e37_79b9_7f4a_7c15)
+                    .map(f64::from_bits)
+                    .take(2_000),
+            )
+            .chain(
+                samples(42)
+                    .map(|n| (n % 40_000) as f64 / 100.0 - 100.0)
+                    .take(2_000),
+            )
+    }
+
+    #[test]
+    fn duty_cycle_is_always_in_range() {
+        let periods = [0, 1, 3, 1_000, 20_000_000, u64::MAX - 1, u64::MAX];
+        for fraction in float_samples() {
+            let duty = match DutyCycle::from_fraction(fraction) {
+                Ok(duty) => duty,
+                Err(e) => {
+                    assert!(fraction.is_nan());
+                    assert!(matches!(e, Error::OutOfRange(_)));
+                    continue;
+                }
+            };
+            assert!((0.0..=1.0).contains(&duty.as_fraction()));
+            if (0.0..=1.0).contains(&fraction) {
+                assert_eq!(duty.as_fraction(), fraction);
+            }
+            for period_ns in periods {
+                assert!(duty.duty_cycle_ns(period_ns).unwrap() <= period_ns);
+            }
+        }
+    }
+
+    #[test]
+    fn duty_cycle_percent_round_trip() {
+        for percent in float_samples().filter(|p| !p.is_nan()) {
+            let duty = DutyCycle::from_percent(percent).unwrap();
+            let expected = (percent / 100.0).clamp(0.0, 1.0) * 100.0;
+            assert_eq!(duty.as_percent(), expected);
+            assert!((0.0..=100.0).contains(&duty.as_percent()));
+        }
+    }
+
+    #[test]
+    fn frequency_is_always_positive_and_finite() {
+        for hz in float_samples() {
+            match Frequency::from_hz(hz) {
+                Ok(frequency) => {
+                    assert!(hz.is_finite() && hz > 0.0);
+                    assert_eq!(frequency.as_hz(), hz);
+                    assert_eq!(Frequency::try_from(hz).unwrap(), frequency);
+                }
+                Err(e) => {
+                    assert!(!hz.is_finite() || hz <= 0.0);
+                    assert!(matches!(e, Error::OutOfRange(_)));
+                }
+            }
+            if let Ok(frequency) = Frequency::from_khz(hz) {
+                assert!(frequency.as_hz().is_finite() && frequency.as_hz() > 0.0);
+                assert_eq!(frequency.as_hz(), hz * 1_000.0);
+            }
+        }
+    }
+
+    #[test]
+    fn duty_ratio_reduced_round_trip() {
+        let mut values = samples(7);
+        for _ in 0..2_000 {
+            let period_ns = values.next().unwrap() % u64::from(u32::MAX) + 1;
+            let duty_cycle_ns = values.next().unwrap() % (period_ns + 1);
+            let ratio = DutyRatio::reduced(duty_cycle_ns, period_ns).unwrap();
+            assert!(ratio.num <= ratio.den);
+            assert_eq!(ratio.duty_cycle_ns(period_ns).unwrap(), duty_cycle_ns);
+            // scaling the period scales the duty cycle exactly
+            assert_eq!(
+                ratio.duty_cycle_ns(period_ns * 3).unwrap(),
+                duty_cycle_ns * 3
+            );
+        }
+    }
+
+    #[test]
+    fn c_names_of_any_length() {
+        let long = "a".repeat(100);
+        for name in ["", "capture", &long[..63], &long[..64], &long] {
+            assert_eq!(
+                with_c_name(name, |c| c.to_str().unwrap().to_string()).unwrap(),
+                name
+            );
+        }
+        assert!(with_c_name("cap\0ture", |_| ()).is_err());
+        assert!(with_c_name(&format!("{}\0", long), |_| ()).is_err());
+    }
+}
@@ -1,3 +1,13 @@
 pub mod common;
 pub mod pwm;
+#[cfg(any(feature = "tokio", feature = "async-std"))]
 pub mod pwm_async;
+pub mod raw;
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+mod rt;
+
+pub use pwm::{all_exported_pins, chips, chips_with_root, count_chips};
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub use pwm_async::{
+    all_exported_pins_async, chips_async, chips_with_root_async, count_chips_async,
+};
@@ -13,102 +13,423 @@
 
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{Seek, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::common;
-use common::{Error, Polarity, Result};
+use common::{
+    align_ns, capture_supported, check_duty_cycle_ns, check_duty_fraction, check_duty_percent,
+    check_period_set, check_pin, duration_to_ns, duty_fraction, f64_to_ns, fraction_of_period,
+    high_fraction, hz_to_period_ns, on_time_to_duty_ns, parse_capture, parse_enabled, parse_npwm,
+    parse_value, period_to_hz, polarity_value, reset_result, round_hz, same_backend,
+    throttle_to_pulse_ns, trim_value, units_to_ns, Capture, DryRun, DryRunDefaults, DutyCycle,
+    DutyRatio, Error, Frequency, IoErrorMapper, Polarity, PwmBackend, PwmConfig, PwmState, Result,
+    SweepScale, SysfsBackend, ESC_ARM_DURATION, ESC_MIN_PULSE_NS, ESC_PERIOD_NS, POLL_INTERVAL,
+    SYSFS_PWM_ROOT,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PwmChip {
     pub number: u32,
+    backend: Arc<dyn PwmBackend>,
 }
 
 #[derive(Debug)]
 pub struct Pwm {
     chip: PwmChip,
     number: u32,
+    polarity_requires_disable: Mutex<Option<bool>>,
+    value_terminator: Option<u8>,
+    inverse_spelling: Option<&'static str>,
+    io_error_mapper: Option<IoErrorMapper>,
+}
+
+/// Disables the PWM when dropped unless it has been disarmed
+struct DisableOnDrop<'a> {
+    pwm: Option<&'a ExportedPwm>,
+}
+
+impl Drop for DisableOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Some(pwm) = self.pwm {
+            let _ = pwm.enable(false);
+        }
+    }
+}
+
+/// Keeps a PWM exported until dropped, created by `Pwm::exported`
+///
+/// The PWM is unexported when the guard is dropped, ignoring any error,
+/// unless `disarm` is called.  The guard dereferences to the
+/// `ExportedPwm`.
+#[derive(Debug)]
+pub struct ExportGuard<'a> {
+    pwm: Option<&'a Pwm>,
+}
+
+impl<'a> ExportGuard<'a> {
+    /// Leave the PWM exported and return it
+    pub fn disarm(mut self) -> &'a ExportedPwm {
+        self.pwm.take().unwrap().as_exported()
+    }
+}
+
+impl Deref for ExportGuard<'_> {
+    type Target = ExportedPwm;
+
+    fn deref(&self) -> &ExportedPwm {
+        self.pwm.unwrap().as_exported()
+    }
+}
+
+impl Drop for ExportGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(pwm) = self.pwm {
+            let _ = pwm.unexport();
+        }
+    }
+}
+
+/// A Pwm which is known to be exported, created by `Pwm::into_exported`
+/// or `Pwm::open_exported`
+///
+/// The attributes of a pin can only be read or written through an
+/// `ExportedPwm`, so configuring a pin which still needs exporting does not
+/// compile.  `with_exported` and `exported` lend one out for the duration
+/// of a closure or guard.  With the `flat-api` feature a `Pwm` dereferences
+/// to an `ExportedPwm`, which restores the old API without this guarantee.
+#[derive(Debug, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct ExportedPwm {
+    pwm: Pwm,
+}
+
+/// The `duty_cycle` attribute of a Pwm held open for repeated writes,
+/// created by `ExportedPwm::open_duty_cycle`
+///
+/// Each write rewinds and rewrites the same file descriptor rather than
+/// reopening the attribute, which matters in tight update loops.  This
+/// relies on sysfs handling every write on its own; a regular file below a
+/// `with_root` tree is not truncated between writes.
+#[derive(Debug)]
+pub struct DutyCycleWriter<'a> {
+    pwm: &'a ExportedPwm,
+    file: Option<(PathBuf, File)>,
+    buf: Vec<u8>,
+}
+
+impl DutyCycleWriter<'_> {
+    /// Write the duty cycle in nanoseconds
+    ///
+    /// Unlike `ExportedPwm::set_duty_cycle_ns` the period is not read first,
+    /// so no `Error::PeriodNotSet` check is made.
+    pub fn write(&mut self, ns: u64) -> Result<()> {
+        let pwm = self.pwm;
+        let (path, file) = match self.file {
+            Some((ref path, ref mut file)) => (path, file),
+            None => return pwm.write_duty_cycle_ns(ns),
+        };
+        self.buf.clear();
+        write!(self.buf, "{}", ns).expect("writing to a Vec cannot fail");
+        if let Some(terminator) = pwm.pwm.value_terminator {
+            self.buf.push(terminator);
+        }
+        file.rewind()
+            .and_then(|()| file.write_all(&self.buf))
+            .map_err(Error::io(path))
+            .map_err(|e| pwm.pwm.map_error(e))
+    }
 }
 
-#[inline]
-fn pwm_file_write(chip: u32, pin: u32, name: &str, value: &[u8]) -> Result<()> {
-    Ok(File::create(format!("/sys/class/pwm/pwmchip{chip}/pwm{pin}/{name}"))?.write_all(value)?)
+/// A set of PWMs which are operated on together
+#[derive(Debug)]
+pub struct PwmGroup {
+    pwms: Vec<ExportedPwm>,
 }
 
-#[inline]
-fn pwm_file_read(chip: u32, pin: u32, name: &str) -> Result<String> {
-    Ok(fs::read_to_string(format!(
-        "/sys/class/pwm/pwmchip{chip}/pwm{pin}/{name}"
-    ))?)
+/// Fluent reconfiguration of a Pwm, created by `ExportedPwm::config`
+///
+/// Only the settings that were given are written when `apply` is called.
+#[derive(Debug)]
+#[must_use]
+pub struct PwmConfigurator<'a> {
+    pwm: &'a ExportedPwm,
+    period_ns: Option<u64>,
+    duty_cycle_ns: Option<u64>,
+    polarity: Option<Polarity>,
+    enabled: Option<bool>,
 }
 
-#[inline]
-fn pwm_file_parse<T: FromStr>(chip: u32, pin: u32, name: &str) -> Result<T> {
-    let s = pwm_file_read(chip, pin, name)?;
-    match s.trim().parse::<T>() {
-        Ok(r) => Ok(r),
-        Err(_) => Err(Error::Unexpected(format!(
-            "Unexpeted value file contents: {:?}",
-            s
-        ))),
+/// Numbers of the `pwmchipN` entries under `root`, sorted
+fn chip_numbers(root: &Path) -> Result<Vec<u32>> {
+    let mut numbers = Vec::new();
+    for entry in fs::read_dir(root).map_err(Error::io(root))? {
+        let name = entry.map_err(Error::io(root))?.file_name();
+        if let Some(number) = name.to_str().and_then(|n| n.strip_prefix("pwmchip")) {
+            if let Ok(number) = number.parse::<u32>() {
+                numbers.push(number);
+            }
+        }
     }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+/// Count the PWM chips present on the system
+///
+/// Returns 0 if `/sys/class/pwm` is empty and an error only if the
+/// directory itself is missing.
+pub fn count_chips() -> Result<usize> {
+    Ok(chip_numbers(Path::new(SYSFS_PWM_ROOT))?.len())
+}
+
+/// Open every PWM chip present on the system, sorted by number
+pub fn chips() -> Result<Vec<PwmChip>> {
+    chips_with_root(SYSFS_PWM_ROOT)
+}
+
+/// Open every PWM chip below `root` rather than `/sys/class/pwm`
+///
+/// See `PwmChip::with_root`.
+pub fn chips_with_root<P: AsRef<Path>>(root: P) -> Result<Vec<PwmChip>> {
+    let root = root.as_ref();
+    chip_numbers(root)?
+        .into_iter()
+        .map(|number| PwmChip::with_root(root, number))
+        .collect()
 }
 
-#[inline]
-fn pwm_file_parse_vec<T: FromStr>(chip: u32, pin: u32, name: &str) -> Result<Vec<T>> {
-    let s = pwm_file_read(chip, pin, name)?.trim().to_string();
-    let vec_s = s.split_whitespace().collect::<Vec<_>>();
-    let mut vec: Vec<T> = vec![];
-    for s in vec_s.iter() {
-        if let Ok(j) = s.parse::<T>() {
-            vec.push(j);
+/// List every exported pin on the system as `(chip, pin)` pairs
+///
+/// Chips which cannot be opened or listed are skipped.
+pub fn all_exported_pins() -> Result<Vec<(u32, u32)>> {
+    let mut pins = Vec::new();
+    for number in chip_numbers(Path::new(SYSFS_PWM_ROOT))? {
+        if let Ok(exported) = PwmChip::new(number).and_then(|chip| chip.exported()) {
+            pins.extend(exported.into_iter().map(|pin| (number, pin)));
         }
     }
-    Ok(vec)
+    Ok(pins)
 }
 
 impl PwmChip {
     pub fn new(number: u32) -> Result<PwmChip> {
-        fs::metadata(&format!("/sys/class/pwm/pwmchip{}", number))?;
-        Ok(PwmChip { number })
+        PwmChip::with_root(SYSFS_PWM_ROOT, number)
     }
 
-    pub fn count(&self) -> Result<u32> {
-        let s = fs::read_to_string(format!("/sys/class/pwm/pwmchip{}/npwm", self.number))?;
-        match s.trim().parse::<u32>() {
-            Ok(n) => Ok(n),
-            Err(_) => Err(Error::Unexpected(format!(
-                "Unexpected npwm contents: {:?}",
-                s
-            ))),
+    /// Open chip `number` below `root` rather than `/sys/class/pwm`
+    ///
+    /// This allows for sysfs being mounted elsewhere, e.g. in a container,
+    /// and for a fake tree of plain files in tests.  Pins of the chip are
+    /// accessed below the same root.
+    pub fn with_root<P: Into<PathBuf>>(root: P, number: u32) -> Result<PwmChip> {
+        let backend = SysfsBackend::with_root(root);
+        backend.chip_dir(number)?;
+        Ok(PwmChip::with_backend(Arc::new(backend), number))
+    }
+
+    /// Use chip `number` of `backend`
+    ///
+    /// See `PwmBackend`.  Nothing is checked, so this never fails.
+    pub fn with_backend(backend: Arc<dyn PwmBackend>, number: u32) -> PwmChip {
+        PwmChip { number, backend }
+    }
+
+    /// The backend through which this chip is accessed
+    ///
+    /// This can be passed to the helpers in `raw`.
+    pub fn backend(&self) -> &dyn PwmBackend {
+        &*self.backend
+    }
+
+    /// Whether `pwmchipN` exists, without opening it
+    ///
+    /// Unlike `new` this never fails; any error is reported as `false`.
+    pub fn exists(number: u32) -> bool {
+        PwmChip::exists_in(SYSFS_PWM_ROOT, number)
+    }
+
+    /// Whether `pwmchipN` exists below `root` rather than `/sys/class/pwm`
+    ///
+    /// See `exists`.
+    pub fn exists_in<P: AsRef<Path>>(root: P, number: u32) -> bool {
+        root.as_ref().join(format!("pwmchip{}", number)).exists()
+    }
+
+    /// List every chip along with its number of channels
+    ///
+    /// Chips whose `npwm` cannot be read are skipped.
+    pub fn list_with_counts() -> Result<Vec<(PwmChip, u32)>> {
+        PwmChip::list_with_counts_in(SYSFS_PWM_ROOT)
+    }
+
+    /// List every chip below `root` along with its number of channels
+    ///
+    /// See `list_with_counts`.
+    pub fn list_with_counts_in<P: AsRef<Path>>(root: P) -> Result<Vec<(PwmChip, u32)>> {
+        let root = root.as_ref();
+        let mut chips = Vec::new();
+        for number in chip_numbers(root)? {
+            if let Ok(chip) = PwmChip::with_root(root, number) {
+                if let Ok(count) = chip.count() {
+                    chips.push((chip, count));
+                }
+            }
         }
+        Ok(chips)
+    }
+
+    pub fn count(&self) -> Result<u32> {
+        parse_npwm(&self.backend.read_chip(self.number, "npwm")?)
+    }
+
+    /// Whether `number` is below the chip's `npwm` channel count
+    pub fn valid_pin(&self, number: u32) -> Result<bool> {
+        Ok(number < self.count()?)
+    }
+
+    /// Return an error unless `number` is a channel of this chip
+    ///
+    /// The error names the chip's actual channel count, which is more
+    /// useful than the ENOENT a later attribute access would fail with.
+    pub fn require_pin(&self, number: u32) -> Result<()> {
+        check_pin(self.number, self.count()?, number)
     }
 
+    /// Export the given pin of this chip
+    ///
+    /// Nothing is written if the pin is already exported.  Pins beyond the
+    /// chip's channel count are rejected like `require_pin` does.  If the
+    /// kernel refuses because another consumer owns the pin
+    /// `Error::PinBusy` is returned.
     pub fn export(&self, number: u32) -> Result<()> {
-        // only export if not already exported
-        if fs::metadata(&format!(
-            "/sys/class/pwm/pwmchip{}/pwm{}",
-            self.number, number
-        ))
-        .is_err()
-        {
-            File::create(format!("/sys/class/pwm/pwmchip{}/export", self.number))?
-                .write_all(number.to_string().as_bytes())?;
-        }
-        Ok(())
+        self.backend.export(self.number, number)
     }
 
     pub fn unexport(&self, number: u32) -> Result<()> {
-        if fs::metadata(&format!(
-            "/sys/class/pwm/pwmchip{}/pwm{}",
-            self.number, number
-        ))
-        .is_ok()
-        {
-            File::create(format!("/sys/class/pwm/pwmchip{}/unexport", self.number))?
-                .write_all(number.to_string().as_bytes())?;
+        self.backend.unexport(self.number, number)
+    }
+
+    /// Disable the given pin of this chip, then unexport it
+    ///
+    /// Some drivers leave the output in an undefined state when an enabled
+    /// channel is unexported.  A missing `enable` file, e.g. because the
+    /// pin is not exported, is not an error.
+    pub fn unexport_safe(&self, number: u32) -> Result<()> {
+        match self.backend.write(self.number, number, "enable", b"0") {
+            Ok(()) => (),
+            Err(Error::Io { ref source, .. }) if source.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
         }
-        Ok(())
+        self.unexport(number)
+    }
+
+    /// Whether the given pin of this chip is currently exported
+    pub fn is_exported(&self, number: u32) -> Result<bool> {
+        self.backend.is_exported(self.number, number)
+    }
+
+    /// Determine whether the chip driver supports capture
+    ///
+    /// The `capture` attribute only exists on exported pins and reading it
+    /// fails with `ENOSYS` when the driver has no capture support.  If a pin
+    /// of the chip is already exported its `capture` attribute is read;
+    /// otherwise the first pin that is not busy is exported as a scratch
+    /// pin, probed and unexported again.  Any error other than `ENOSYS`,
+    /// e.g. a capture timing out on an idle input, means capture is
+    /// supported.
+    ///
+    /// On drivers with capture support the read runs a real capture, which
+    /// can block for about a second.  Use `supports_capture_if_exported` to
+    /// avoid exporting anything.
+    pub fn supports_capture(&self) -> Result<bool> {
+        if let Some(supported) = self.supports_capture_if_exported()? {
+            return Ok(supported);
+        }
+        for pin in 0..self.count()? {
+            match self.export(pin) {
+                Ok(()) => (),
+                Err(Error::PinBusy { .. }) => continue,
+                Err(e) => return Err(e),
+            }
+            let read = self.backend.read(self.number, pin, "capture");
+            self.unexport(pin)?;
+            return Ok(capture_supported(&read));
+        }
+        Err(Error::Unexpected(format!(
+            "pwmchip{} has no free pin to probe for capture support",
+            self.number
+        )))
+    }
+
+    /// Determine whether the chip driver supports capture without exporting
+    ///
+    /// Like `supports_capture`, but only an already exported pin is probed.
+    /// If nothing on the chip is exported `Ok(None)` is returned rather
+    /// than guessing.
+    pub fn supports_capture_if_exported(&self) -> Result<Option<bool>> {
+        match self.exported()?.first() {
+            Some(pin) => {
+                let read = self.backend.read(self.number, *pin, "capture");
+                Ok(Some(capture_supported(&read)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Find exported pins of this chip which are not in `expected`
+    ///
+    /// This is intended for recovery after a crash left pins exported: the
+    /// returned pins can be reset or unexported.
+    pub fn orphaned_pins(&self, expected: &[u32]) -> Result<Vec<u32>> {
+        let mut pins = self.exported()?;
+        pins.retain(|pin| !expected.contains(pin));
+        Ok(pins)
+    }
+
+    /// Disable every exported pin and set its duty cycle to 0
+    ///
+    /// The pins are also unexported if `unexport` is set.  Every pin is
+    /// attempted even if resetting an earlier one fails, and
+    /// `Error::ResetFailed` then lists the error of every failed pin.
+    pub fn reset_all_exported(&self, unexport: bool) -> Result<()> {
+        let mut failures = Vec::new();
+        for pin in self.exported()? {
+            let reset = self
+                .backend
+                .write(self.number, pin, "enable", b"0")
+                .and_then(|()| self.backend.write(self.number, pin, "duty_cycle", b"0"))
+                .and_then(|()| if unexport { self.unexport(pin) } else { Ok(()) });
+            if let Err(e) = reset {
+                failures.push((pin, e));
+            }
+        }
+        reset_result(self.number, failures)
+    }
+
+    /// Numbers of the pins of this chip which are currently exported, sorted
+    ///
+    /// For sysfs these are the `pwmN` entries of the chip directory.
+    pub fn exported(&self) -> Result<Vec<u32>> {
+        self.backend.exported(self.number)
+    }
+
+    /// Handles for every pin of this chip which is currently exported
+    ///
+    /// This is handy for cleaning up pins left exported by an earlier run.
+    pub fn channels(&self) -> Result<Vec<Pwm>> {
+        Ok(self
+            .exported()?
+            .into_iter()
+            .map(|number| Pwm::on_chip(self.clone(), number))
+            .collect())
     }
 }
 
@@ -117,18 +438,184 @@ impl Pwm {
     ///
     /// This function does not export the Pwm pin
     pub fn new(chip: u32, number: u32) -> Result<Pwm> {
-        let chip: PwmChip = PwmChip::new(chip)?;
-        Ok(Pwm { chip, number })
+        Pwm::with_root(SYSFS_PWM_ROOT, chip, number)
+    }
+
+    /// Create a new Pwm for a chip below `root` rather than `/sys/class/pwm`
+    ///
+    /// See `PwmChip::with_root`.  This function does not export the pin.
+    pub fn with_root<P: Into<PathBuf>>(root: P, chip: u32, number: u32) -> Result<Pwm> {
+        let chip = PwmChip::with_root(root, chip)?;
+        Ok(Pwm::on_chip(chip, number))
+    }
+
+    /// Create a Pwm for pin `number` of an already opened chip
+    fn on_chip(chip: PwmChip, number: u32) -> Pwm {
+        Pwm {
+            chip,
+            number,
+            polarity_requires_disable: Mutex::new(None),
+            value_terminator: None,
+            inverse_spelling: None,
+            io_error_mapper: None,
+        }
+    }
+
+    /// Open a pin which must already be exported
+    ///
+    /// Unlike `into_exported`, this returns an error if the pin directory
+    /// does not exist rather than exporting the pin.
+    pub fn open_exported(chip: u32, number: u32) -> Result<ExportedPwm> {
+        let pwm = Pwm::new(chip, number)?;
+        if !pwm.is_exported()? {
+            return Err(Error::Io {
+                path: Path::new(SYSFS_PWM_ROOT).join(format!("pwmchip{}/pwm{}", chip, number)),
+                source: io::Error::new(io::ErrorKind::NotFound, "pin is not exported"),
+            });
+        }
+        Ok(ExportedPwm { pwm })
+    }
+
+    /// Create a dry-run Pwm which never touches sysfs
+    ///
+    /// Writes are logged to stderr and remembered, reads return the last
+    /// value written or a zeroed default.  This is intended for testing
+    /// and local development on machines without PWM hardware only.
+    pub fn dry_run(chip: u32, number: u32) -> Pwm {
+        Pwm::dry_run_with(chip, number, DryRunDefaults::default())
+    }
+
+    /// Create a dry-run Pwm whose reads start out with the given values
+    ///
+    /// See `Pwm::dry_run`; this is intended for testing only.
+    pub fn dry_run_with(chip: u32, number: u32, defaults: DryRunDefaults) -> Pwm {
+        Pwm::with_backend(Arc::new(DryRun::new(defaults)), chip, number)
+    }
+
+    /// Create a dry-run Pwm which passes its log lines to `logger`
+    ///
+    /// Rather than going to stderr, every write, export and unexport is
+    /// described in one line given to `logger`.  See `Pwm::dry_run`.
+    pub fn dry_run_with_logger<F>(
+        chip: u32,
+        number: u32,
+        defaults: DryRunDefaults,
+        logger: F,
+    ) -> Pwm
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        Pwm::with_backend(
+            Arc::new(DryRun::with_logger(defaults, logger)),
+            chip,
+            number,
+        )
+    }
+
+    /// Create a Pwm whose attributes are accessed through `backend`
+    ///
+    /// See `PwmBackend`.  Nothing is checked or exported.
+    pub fn with_backend(backend: Arc<dyn PwmBackend>, chip: u32, number: u32) -> Pwm {
+        Pwm::on_chip(PwmChip::with_backend(backend, chip), number)
+    }
+
+    #[inline]
+    fn write_attr(&self, name: &str, value: &[u8]) -> Result<()> {
+        let terminated;
+        let value = match self.value_terminator {
+            Some(terminator) => {
+                terminated = [value, &[terminator]].concat();
+                &terminated[..]
+            }
+            None => value,
+        };
+        self.chip
+            .backend
+            .write(self.chip.number, self.number, name, value)
+            .map_err(|e| self.map_error(e))
+    }
+
+    #[inline]
+    fn read_attr(&self, name: &str) -> Result<String> {
+        self.chip
+            .backend
+            .read(self.chip.number, self.number, name)
+            .map_err(|e| self.map_error(e))
+    }
+
+    #[inline]
+    fn parse_attr<T: FromStr>(&self, name: &str) -> Result<T> {
+        parse_value(&self.read_attr(name)?)
+    }
+
+    /// The chip this PWM belongs to
+    pub fn chip(&self) -> &PwmChip {
+        &self.chip
+    }
+
+    /// The number of the chip this PWM belongs to
+    pub fn chip_number(&self) -> u32 {
+        self.chip.number
+    }
+
+    /// The channel number of this PWM within its chip
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// Set a byte to append to every value written to sysfs
+    ///
+    /// Values are written without a terminator by default.  Some drivers
+    /// insist on a trailing newline, which `Some(b'\n')` provides.
+    pub fn set_value_terminator(&mut self, terminator: Option<u8>) {
+        self.value_terminator = terminator;
+    }
+
+    /// Choose how inverse polarity is spelled when written
+    ///
+    /// "inversed" is written by default, which is what the kernel's PWM
+    /// core expects.  Some older drivers only accept another spelling such
+    /// as "inverted", which `Some("inverted")` selects.  Reading accepts
+    /// every known spelling regardless.
+    pub fn set_inverse_spelling(&mut self, spelling: Option<&'static str>) {
+        self.inverse_spelling = spelling;
+    }
+
+    /// Install a mapping from I/O errors to errors of this crate
+    ///
+    /// The raw `io::Error` of every failed attribute read or write is
+    /// passed to `mapper` first, including EACCES and EPERM; if it returns
+    /// `Some` that error is returned instead, otherwise the error is
+    /// reported as usual, e.g. as `Error::PermissionDenied`.  This allows
+    /// for platforms which report the same condition with a different
+    /// errno.
+    pub fn set_io_error_mapper<F>(&mut self, mapper: F)
+    where
+        F: Fn(&io::Error) -> Option<Error> + Send + Sync + 'static,
+    {
+        self.io_error_mapper = Some(IoErrorMapper::new(mapper));
+    }
+
+    #[inline]
+    fn map_error(&self, err: Error) -> Error {
+        match self.io_error_mapper {
+            Some(ref mapper) => mapper.map(err),
+            None => err,
+        }
     }
 
     /// Run a closure with the GPIO exported
+    ///
+    /// The closure is passed this PWM as an `ExportedPwm`.  It is
+    /// unexported afterwards, and if the closure succeeds but unexporting
+    /// fails, the unexport error is returned.
     #[inline]
     pub fn with_exported<F>(&self, closure: F) -> Result<()>
     where
-        F: FnOnce() -> Result<()>,
+        F: FnOnce(&ExportedPwm) -> Result<()>,
     {
         self.export()?;
-        match closure() {
+        match closure(self.as_exported()) {
             Ok(()) => self.unexport(),
             Err(e) => match self.unexport() {
                 Ok(()) => Err(e),
@@ -140,114 +627,1699 @@ impl Pwm {
         }
     }
 
+    /// Run a closure with the PWM exported, leaving it exported on success
+    ///
+    /// As with `with_exported` the PWM is unexported again if the closure
+    /// fails, but on success it stays exported for later use.
+    #[inline]
+    pub fn with_exported_keep<F>(&self, closure: F) -> Result<()>
+    where
+        F: FnOnce(&ExportedPwm) -> Result<()>,
+    {
+        self.export()?;
+        match closure(self.as_exported()) {
+            Ok(()) => Ok(()),
+            Err(e) => match self.unexport() {
+                Ok(()) => Err(e),
+                Err(ue) => Err(Error::Unexpected(format!(
+                    "Failed unexporting due to:\n{}\nwhile handling:\n{}",
+                    ue, e
+                ))),
+            },
+        }
+    }
+
     /// Export the Pwm for use
     pub fn export(&self) -> Result<()> {
         self.chip.export(self.number)
     }
 
+    /// Export the PWM and wait for its attributes to appear
+    ///
+    /// The attribute files of a newly exported pin are created
+    /// asynchronously, and udev may still be adjusting their permissions
+    /// when `export` returns.  This polls until `period` can be read and
+    /// returns `Error::Timeout` if it cannot within `timeout`.
+    pub fn export_and_wait(&self, timeout: Duration) -> Result<()> {
+        self.export()?;
+        let start = Instant::now();
+        loop {
+            if self.read_attr("period").is_ok() {
+                return Ok(());
+            }
+            let waited = start.elapsed();
+            if waited >= timeout {
+                return Err(Error::Timeout {
+                    operation: format!(
+                        "waiting for pwmchip{}/pwm{} to appear",
+                        self.chip.number, self.number
+                    ),
+                    waited,
+                });
+            }
+            thread::sleep(POLL_INTERVAL.min(timeout - waited));
+        }
+    }
+
     /// Unexport the PWM
     pub fn unexport(&self) -> Result<()> {
         self.chip.unexport(self.number)
     }
 
+    /// Disable the PWM, then unexport it
+    ///
+    /// See `PwmChip::unexport_safe`.
+    pub fn unexport_safe(&self) -> Result<()> {
+        match self.as_exported().enable(false) {
+            Ok(()) => (),
+            Err(Error::Io { ref source, .. }) if source.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        self.unexport()
+    }
+
+    /// Whether the PWM is currently exported
+    ///
+    /// The chip's backend is asked; a dry-run Pwm always reports being
+    /// exported.
+    pub fn is_exported(&self) -> Result<bool> {
+        self.chip.is_exported(self.number)
+    }
+
+    /// Export the PWM, turning it into an `ExportedPwm`
+    pub fn into_exported(self) -> Result<ExportedPwm> {
+        self.export()?;
+        Ok(ExportedPwm { pwm: self })
+    }
+
+    /// View this Pwm as exported without checking
+    ///
+    /// Only for pins this crate has just exported, or where an unexported
+    /// pin merely makes the attribute accesses fail.
+    fn as_exported(&self) -> &ExportedPwm {
+        // SAFETY: `ExportedPwm` is a `repr(transparent)` wrapper of `Pwm`
+        unsafe { &*(self as *const Pwm as *const ExportedPwm) }
+    }
+
+    /// Export the PWM until the returned guard is dropped
+    ///
+    /// See `ExportGuard`; unlike `with_exported` this does not confine the
+    /// use of the PWM to a closure.
+    pub fn exported(&self) -> Result<ExportGuard<'_>> {
+        self.export()?;
+        Ok(ExportGuard { pwm: Some(self) })
+    }
+}
+
+impl ExportedPwm {
+    /// Unexport the PWM and return the plain handle
+    ///
+    /// On failure the handle is dropped; a new one can be created with
+    /// `Pwm::new`.
+    pub fn unexport(self) -> Result<Pwm> {
+        self.pwm.unexport()?;
+        Ok(self.pwm)
+    }
+
+    /// The chip this PWM belongs to
+    pub fn chip(&self) -> &PwmChip {
+        &self.pwm.chip
+    }
+
+    /// The number of the chip this PWM belongs to
+    pub fn chip_number(&self) -> u32 {
+        self.pwm.chip.number
+    }
+
+    /// The channel number of this PWM within its chip
+    pub fn number(&self) -> u32 {
+        self.pwm.number
+    }
+
+    /// Run a closure, restoring the prior state of the PWM if it fails
+    ///
+    /// Period, duty cycle, polarity and enable are read before running the
+    /// closure.  If the closure returns an error they are written back in a
+    /// safe order before the original error is returned.
+    pub fn transaction<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&ExportedPwm) -> Result<()>,
+    {
+        let period_ns = self.get_period_ns()?;
+        let duty_cycle_ns = self.get_duty_cycle_ns()?;
+        let polarity = self.get_polarity()?;
+        let enabled = self.get_enabled()?;
+        match f(self) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let restored = match self.enable(false) {
+                    Ok(()) => self
+                        .config()
+                        .polarity(polarity)
+                        .period_ns(period_ns)
+                        .duty_ns(duty_cycle_ns)
+                        .enabled(enabled)
+                        .apply(),
+                    Err(re) => Err(re),
+                };
+                match restored {
+                    Ok(()) => Err(e),
+                    Err(re) => Err(Error::Unexpected(format!(
+                        "Failed restoring state due to:\n{}\nwhile handling:\n{}",
+                        re, e
+                    ))),
+                }
+            }
+        }
+    }
+
     /// Enable/Disable the PWM Signal
     pub fn enable(&self, enable: bool) -> Result<()> {
-        pwm_file_write(
-            self.chip.number,
-            self.number,
-            "enable",
-            &(enable as u8).to_string().as_bytes(),
-        )
+        self.pwm
+            .write_attr("enable", (enable as u8).to_string().as_bytes())
+    }
+
+    /// Enable the PWM after checking that its configuration makes sense
+    ///
+    /// Nothing is enabled if the period is 0, which returns
+    /// `Error::PeriodNotSet`, or if the duty cycle exceeds the period, which
+    /// returns `Error::OutOfRange`.  Use `enable` to skip the check.
+    pub fn enable_checked(&self) -> Result<()> {
+        let period_ns = self.get_period_ns()?;
+        let duty_cycle_ns = self.get_duty_cycle_ns()?;
+        check_period_set(period_ns)?;
+        check_duty_cycle_ns(duty_cycle_ns, period_ns)?;
+        self.enable(true)
+    }
+
+    /// Enable the PWM for `duration`, then disable it again
+    ///
+    /// The output is disabled even if this unwinds while waiting.
+    pub fn pulse_for(&self, duration: Duration) -> Result<()> {
+        self.enable(true)?;
+        let mut guard = DisableOnDrop { pwm: Some(self) };
+        thread::sleep(duration);
+        guard.pwm = None;
+        self.enable(false)
+    }
+
+    /// Enable/Disable the PWM Signal and wait for the change to take effect
+    ///
+    /// After writing, `get_enabled` is polled until it reflects `enable`.
+    /// If it does not within `timeout`, `Error::Timeout` is returned.
+    pub fn enable_and_confirm(&self, enable: bool, timeout: Duration) -> Result<()> {
+        self.enable(enable)?;
+        let start = Instant::now();
+        loop {
+            if self.get_enabled()? == enable {
+                return Ok(());
+            }
+            let waited = start.elapsed();
+            if waited >= timeout {
+                return Err(Error::Timeout {
+                    operation: format!("waiting for enable to become {}", enable as u8),
+                    waited,
+                });
+            }
+            thread::sleep(POLL_INTERVAL.min(timeout - waited));
+        }
     }
 
     /// Query the state of enable for a given PWM pin
+    ///
+    /// Contents other than the recognised on/off spellings are reported as
+    /// `Error::Unexpected` with the raw value rather than a panic.
     pub fn get_enabled(&self) -> Result<bool> {
-        Ok(
-            match pwm_file_read(self.chip.number, self.number, "enable")?.trim() {
-                "1" => true,
-                "0" => false,
-                _ => panic!("enable != 1|0 should be unreachable"),
-            },
-        )
+        parse_enabled(&self.pwm.read_attr("enable")?)
     }
 
-    /// Get the currently configured duty_cycle in nanoseconds
-    pub fn get_duty_cycle_ns(&self) -> Result<u32> {
-        pwm_file_parse::<u32>(self.chip.number, self.number, "duty_cycle")
+    /// Read period, duty cycle, polarity and enable in a single pass
+    ///
+    /// Sysfs offers no way to read several attributes atomically, so this
+    /// is a best-effort snapshot, but it keeps the reads back-to-back.
+    pub fn read_all(&self) -> Result<PwmState> {
+        Ok(PwmState {
+            period_ns: self.get_period_ns()?,
+            duty_cycle_ns: self.get_duty_cycle_ns()?,
+            polarity: self.get_polarity()?,
+            enabled: self.get_enabled()?,
+        })
     }
 
-    /// Get the capture
-    pub fn get_capture(&self) -> Result<(u32, u32)> {
-        let t = pwm_file_parse_vec::<u32>(self.chip.number, self.number, "capture")?;
-        if t.len() == 2 {
-            Ok((t[0], t[1]))
-        } else {
-            Err(Error::Unexpected(format!("Failed exporting")))
+    /// Snapshot the period, duty cycle, polarity and enable
+    ///
+    /// This is the same best-effort snapshot as `read_all`.
+    pub fn state(&self) -> Result<PwmState> {
+        self.read_all()
+    }
+
+    /// Read the period and duty cycle as a `(period_ns, duty_cycle_ns)` pair
+    ///
+    /// The two attributes cannot be read atomically over sysfs, so a
+    /// concurrent write may be seen half-applied.  If the duty cycle read
+    /// exceeds the period both are read once more, and an error is
+    /// returned if the pair is still inconsistent.
+    pub fn read_consistent(&self) -> Result<(u64, u64)> {
+        for _ in 0..2 {
+            let period_ns = self.get_period_ns()?;
+            let duty_cycle_ns = self.get_duty_cycle_ns()?;
+            if duty_cycle_ns <= period_ns {
+                return Ok((period_ns, duty_cycle_ns));
+            }
         }
+        Err(Error::Unexpected(format!(
+            "pwmchip{}/pwm{} reports a duty cycle larger than its period",
+            self.pwm.chip.number, self.pwm.number
+        )))
     }
 
-    /// The active time of the PWM signal
+    /// Write back a state previously returned by `read_all`
     ///
-    /// Value is in nanoseconds and must be less than the period.
-    pub fn set_duty_cycle_ns(&self, duty_cycle_ns: u32) -> Result<()> {
-        pwm_file_write(
-            self.chip.number,
-            self.number,
-            "duty_cycle",
-            &duty_cycle_ns.to_string().as_bytes(),
-        )
+    /// The pin is exported if necessary, polarity, period and duty cycle
+    /// are written in an order the kernel accepts, and the pin is left
+    /// enabled or disabled as recorded in `state`.
+    pub fn restore(&self, state: &PwmState) -> Result<()> {
+        self.pwm.export()?;
+        self.config()
+            .enabled(state.enabled)
+            .polarity(state.polarity)
+            .period_ns(state.period_ns)
+            .duty_ns(state.duty_cycle_ns)
+            .apply()
     }
 
-    /// Get the currently configured duty_cycle as percentage of period
-    pub fn get_duty_cycle(&self) -> Result<f32> {
-        Ok((self.get_duty_cycle_ns()? as f32) / (self.get_period_ns()? as f32))
+    /// Write every setting in `cfg`
+    ///
+    /// The output is disabled first if `cfg` leaves it disabled, then the
+    /// polarity is set, then period and duty cycle in an order the kernel
+    /// accepts, and the output is enabled last if requested.  Sysfs offers
+    /// no transactions, so a failure part-way leaves the earlier writes in
+    /// place.
+    pub fn apply(&self, cfg: &PwmConfig) -> Result<()> {
+        self.config()
+            .enabled(cfg.enabled)
+            .polarity(cfg.polarity)
+            .period_ns(cfg.period_ns)
+            .duty_ns(cfg.duty_cycle_ns)
+            .apply()
     }
 
-    /// The active time of the PWM signal
+    /// Get the currently configured duty_cycle in nanoseconds
+    pub fn get_duty_cycle_ns(&self) -> Result<u64> {
+        self.pwm.parse_attr::<u64>("duty_cycle")
+    }
+
+    /// Get the capture
     ///
-    /// Value is as percentage of period.
-    pub fn set_duty_cycle(&self, duty_cycle: f32) -> Result<()> {
-        self.set_duty_cycle_ns((self.get_period_ns()? as f32 * duty_cycle).round() as u32)
+    /// Contents other than exactly two numbers, such as the empty string
+    /// some drivers without capture support report, are returned as
+    /// `Error::Unexpected` including the raw contents.
+    pub fn get_capture(&self) -> Result<Capture> {
+        parse_capture(&self.pwm.read_attr("capture")?)
     }
 
-    /// Get the currently configured period in nanoseconds
-    pub fn get_period_ns(&self) -> Result<u32> {
-        pwm_file_parse::<u32>(self.chip.number, self.number, "period")
+    /// Get the capture as a `(period_ns, duty_cycle_ns)` tuple
+    #[deprecated(note = "use `get_capture`, which returns a `Capture`")]
+    pub fn get_capture_raw(&self) -> Result<(u64, u64)> {
+        self.get_capture().map(<(u64, u64)>::from)
     }
 
-    /// The period of the PWM signal in Nanoseconds
-    pub fn set_period_ns(&self, period_ns: u32) -> Result<()> {
-        pwm_file_write(
-            self.chip.number,
-            self.number,
-            "period",
-            &period_ns.to_string().as_bytes(),
-        )
+    /// Read the capture into a caller-provided buffer
+    ///
+    /// This avoids the allocations `get_capture` makes on every call,
+    /// which matters when polling the capture at a high rate.  `buf` is
+    /// cleared and holds the raw attribute contents afterwards.
+    pub fn read_capture_into(&self, buf: &mut String) -> Result<Capture> {
+        self.pwm
+            .chip
+            .backend
+            .read_into(self.pwm.chip.number, self.pwm.number, "capture", buf)
+            .map_err(|e| self.pwm.map_error(e))?;
+        parse_capture(buf)
     }
 
-    /// Set the polarity of the PWM signal
-    pub fn set_polarity(&self, polarity: Polarity) -> Result<()> {
-        pwm_file_write(
-            self.chip.number,
-            self.number,
-            "polarity",
-            match polarity {
-                Polarity::Normal => b"normal",
-                Polarity::Inverse => b"inversed",
-            },
-        )
+    #[inline]
+    fn write_duty_cycle_ns(&self, duty_cycle_ns: u64) -> Result<()> {
+        self.pwm
+            .write_attr("duty_cycle", duty_cycle_ns.to_string().as_bytes())
     }
 
-    /// Get the polarity of the PWM signal
-    pub fn get_polarity(&self) -> Result<Polarity> {
-        let s = pwm_file_read(self.chip.number, self.number, "polarity")?;
-        match s.trim() {
-            "normal" => Ok(Polarity::Normal),
-            "inversed" => Ok(Polarity::Inverse),
-            _ => Err(Error::Unexpected(format!(
-                "Unexpected polarity file contents: {:?}",
-                s
-            ))),
+    /// The active time of the PWM signal
+    ///
+    /// Value is in nanoseconds and must not exceed the period.  The period
+    /// is read first: `Error::PeriodNotSet` is returned if a non-zero duty
+    /// cycle is requested while the period is still 0, and
+    /// `Error::OutOfRange` if the duty cycle is larger than the period.
+    pub fn set_duty_cycle_ns(&self, duty_cycle_ns: u64) -> Result<()> {
+        if duty_cycle_ns > 0 {
+            check_duty_cycle_ns(duty_cycle_ns, self.get_period_ns()?)?;
+        }
+        self.write_duty_cycle_ns(duty_cycle_ns)
+    }
+
+    /// Write the duty cycle in nanoseconds without reading the period
+    ///
+    /// This skips the checks made by `set_duty_cycle_ns`, saving a read;
+    /// a duty cycle the kernel rejects surfaces as `Error::Io`.
+    pub fn set_duty_cycle_ns_unchecked(&self, duty_cycle_ns: u64) -> Result<()> {
+        self.write_duty_cycle_ns(duty_cycle_ns)
+    }
+
+    /// Open the `duty_cycle` attribute once for fast repeated writes
+    ///
+    /// For a backend without files, see `PwmBackend::open_write`, the
+    /// returned writer goes through it like `set_duty_cycle_ns` does.
+    pub fn open_duty_cycle(&self) -> Result<DutyCycleWriter<'_>> {
+        let file = self
+            .pwm
+            .chip
+            .backend
+            .open_write(self.pwm.chip.number, self.pwm.number, "duty_cycle")
+            .map_err(|e| self.pwm.map_error(e))?;
+        Ok(DutyCycleWriter {
+            pwm: self,
+            file,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Get the currently configured duty_cycle as percentage of period
+    ///
+    /// The duty cycle and period are read separately, so a concurrent change
+    /// can briefly make the duty cycle appear larger than the period.  The
+    /// result is clamped to `0.0..=1.0` so it is always a valid fraction.
+    /// Returns `Error::PeriodNotSet` if the period is still 0.
+    pub fn get_duty_cycle(&self) -> Result<f32> {
+        let duty_cycle_ns = self.get_duty_cycle_ns()?;
+        duty_fraction(duty_cycle_ns, self.get_period_ns()?)
+    }
+
+    /// The active time of the PWM signal
+    ///
+    /// Value is as fraction of period and must be within 0.0..=1.0;
+    /// anything else, including NaN, is rejected with `Error::OutOfRange`.
+    /// Use `set_duty` with `DutyCycle::from_fraction` to clamp instead.
+    /// Returns `Error::PeriodNotSet` if the period is still 0.
+    pub fn set_duty_cycle(&self, duty_cycle: f32) -> Result<()> {
+        check_duty_fraction(duty_cycle)?;
+        let period_ns = self.get_period_ns()?;
+        check_period_set(period_ns)?;
+        self.write_duty_cycle_ns(fraction_of_period(period_ns, f64::from(duty_cycle))?)
+    }
+
+    /// Set the duty cycle as a fraction of the current period
+    ///
+    /// Returns `Error::PeriodNotSet` if the period is still 0.
+    pub fn set_duty(&self, duty_cycle: DutyCycle) -> Result<()> {
+        let period_ns = self.get_period_ns()?;
+        check_period_set(period_ns)?;
+        self.write_duty_cycle_ns(duty_cycle.duty_cycle_ns(period_ns)?)
+    }
+
+    /// Move the duty cycle towards `target` by at most `max_step_per_call`
+    ///
+    /// Both are fractions of the period as used by `set_duty_cycle`.  The
+    /// duty cycle written is returned, so this can be called once per
+    /// control loop tick until it returns `target`.
+    pub fn slew_to_duty(&self, target: f32, max_step_per_call: f32) -> Result<f32> {
+        check_duty_fraction(target)?;
+        if !max_step_per_call.is_finite() || max_step_per_call < 0.0 {
+            return Err(Error::OutOfRange(format!(
+                "Slew step must be non-negative and finite, got {}",
+                max_step_per_call
+            )));
+        }
+        let current = self.get_duty_cycle()?;
+        let next = if (target - current).abs() <= max_step_per_call {
+            target
+        } else {
+            current + max_step_per_call.copysign(target - current)
+        };
+        self.set_duty_cycle(next)?;
+        Ok(next)
+    }
+
+    /// Get the fraction of the period during which the output is high
+    ///
+    /// Unlike `get_duty_cycle` this accounts for polarity: with inverse
+    /// polarity the output is high for `period - duty_cycle`.  Returns
+    /// `Error::PeriodNotSet` if the period is 0.
+    pub fn effective_duty_cycle(&self) -> Result<f64> {
+        let period_ns = self.get_period_ns()?;
+        let duty_cycle_ns = self.get_duty_cycle_ns()?;
+        high_fraction(duty_cycle_ns, period_ns, self.get_polarity()?)
+    }
+
+    /// Write a sequence of duty cycle fractions at a bounded rate
+    ///
+    /// Consecutive writes are at least `min_interval` apart.  A value which
+    /// arrives before the interval is up is held back and replaced by any
+    /// newer one, so only the latest value is written once the interval
+    /// has passed; the last held value is written after sleeping out the
+    /// rest of the interval.  Time spent producing values counts towards
+    /// the interval.  The period is read once up front, and values which
+    /// would not change the duty cycle are not written.
+    pub fn apply_values<I>(&self, values: I, min_interval: Duration) -> Result<()>
+    where
+        I: IntoIterator<Item = f32>,
+    {
+        let period_ns = self.get_period_ns()?;
+        check_period_set(period_ns)?;
+        let mut duty_cycle_ns = self.get_duty_cycle_ns()?;
+        let mut last_write: Option<Instant> = None;
+        let mut pending = None;
+        for value in values {
+            check_duty_fraction(value)?;
+            let next_ns = fraction_of_period(period_ns, f64::from(value))?;
+            if last_write.is_some_and(|t| t.elapsed() < min_interval) {
+                pending = Some(next_ns);
+                continue;
+            }
+            pending = None;
+            if next_ns != duty_cycle_ns {
+                self.write_duty_cycle_ns(next_ns)?;
+                duty_cycle_ns = next_ns;
+                last_write = Some(Instant::now());
+            }
+        }
+        match (pending, last_write) {
+            (Some(next_ns), Some(last_write)) if next_ns != duty_cycle_ns => {
+                if let Some(rest) = min_interval.checked_sub(last_write.elapsed()) {
+                    thread::sleep(rest);
+                }
+                self.write_duty_cycle_ns(next_ns)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Set the duty cycle to an exact fraction of the period
+    ///
+    /// The duty cycle is computed as `period * num / den` in integer
+    /// arithmetic, so e.g. 1/3 carries no floating point rounding.
+    pub fn set_duty_ratio(&self, ratio: DutyRatio) -> Result<()> {
+        let period_ns = self.get_period_ns()?;
+        check_period_set(period_ns)?;
+        self.write_duty_cycle_ns(ratio.duty_cycle_ns(period_ns)?)
+    }
+
+    /// Set the duty cycle to `num / den` of the period
+    ///
+    /// Shorthand for `set_duty_ratio`; `den` must be non-zero and `num`
+    /// must not exceed it.
+    pub fn set_duty_cycle_ratio(&self, num: u32, den: u32) -> Result<()> {
+        self.set_duty_ratio(DutyRatio { num, den })
+    }
+
+    /// Get the duty cycle as a fraction of the period in lowest terms
+    ///
+    /// Returns `Error::PeriodNotSet` if the period is 0.
+    pub fn get_duty_ratio(&self) -> Result<DutyRatio> {
+        let period_ns = self.get_period_ns()?;
+        check_period_set(period_ns)?;
+        DutyRatio::reduced(self.get_duty_cycle_ns()?, period_ns)
+    }
+
+    /// Set how long the output is high each period, accounting for polarity
+    ///
+    /// With normal polarity the duty cycle is `on_ns`; with inverse
+    /// polarity it is `period - on_ns`.  `on_ns` must not exceed the period,
+    /// otherwise `Error::OutOfRange` is returned.
+    pub fn set_on_time_ns(&self, on_ns: u64) -> Result<()> {
+        let period_ns = self.get_period_ns()?;
+        let duty_cycle_ns = on_time_to_duty_ns(on_ns, period_ns, self.get_polarity()?)?;
+        self.write_duty_cycle_ns(duty_cycle_ns)
+    }
+
+    /// Get the currently configured period in nanoseconds
+    pub fn get_period_ns(&self) -> Result<u64> {
+        self.pwm.parse_attr::<u64>("period")
+    }
+
+    /// Set the period to that of `frequency`, rounded to whole nanoseconds
+    ///
+    /// `frequency` is either a `Frequency` or a plain `f64` in Hz, which
+    /// must be positive and give a period of at least 1 ns.  The duty cycle
+    /// in nanoseconds is left unchanged, so the duty cycle percentage
+    /// changes unless the caller sets the duty cycle again.
+    pub fn set_frequency<F>(&self, frequency: F) -> Result<()>
+    where
+        F: TryInto<Frequency>,
+        Error: From<F::Error>,
+    {
+        let frequency = frequency.try_into()?;
+        self.set_period_ns(hz_to_period_ns(frequency.as_hz())?)
+    }
+
+    /// Get the frequency of the PWM signal in Hz
+    ///
+    /// Returns `Error::PeriodNotSet` if the period is 0.
+    pub fn get_frequency(&self) -> Result<f64> {
+        period_to_hz(self.get_period_ns()?)
+    }
+
+    /// Get the frequency of the PWM signal rounded to whole Hz
+    ///
+    /// This is meant for display, where 49.99998 Hz should read as 50 Hz.
+    /// Returns `Error::PeriodNotSet` if the period is 0.
+    pub fn get_frequency_hz_rounded(&self) -> Result<u32> {
+        round_hz(period_to_hz(self.get_period_ns()?)?)
+    }
+
+    /// The period of the PWM signal in Nanoseconds
+    pub fn set_period_ns(&self, period_ns: u64) -> Result<()> {
+        self.pwm
+            .write_attr("period", period_ns.to_string().as_bytes())
+    }
+
+    /// Set the period only if it differs from the current one
+    ///
+    /// Returns whether anything was written.  Skipping redundant writes
+    /// saves syscalls and avoids the glitch some drivers produce on every
+    /// write.
+    pub fn set_period_ns_if_changed(&self, period_ns: u64) -> Result<bool> {
+        if self.get_period_ns()? == period_ns {
+            return Ok(false);
+        }
+        self.set_period_ns(period_ns)?;
+        Ok(true)
+    }
+
+    /// Set the duty cycle only if it differs from the current one
+    ///
+    /// Returns whether anything was written.
+    pub fn set_duty_cycle_ns_if_changed(&self, duty_cycle_ns: u64) -> Result<bool> {
+        if self.get_duty_cycle_ns()? == duty_cycle_ns {
+            return Ok(false);
+        }
+        self.set_duty_cycle_ns(duty_cycle_ns)?;
+        Ok(true)
+    }
+
+    /// Set the polarity only if it differs from the current one
+    ///
+    /// Returns whether anything was written.
+    pub fn set_polarity_if_changed(&self, polarity: Polarity) -> Result<bool> {
+        if self.get_polarity()? == polarity {
+            return Ok(false);
+        }
+        self.set_polarity(polarity)?;
+        Ok(true)
+    }
+
+    /// Enable or disable the PWM only if that changes its state
+    ///
+    /// Returns whether anything was written.
+    pub fn enable_if_changed(&self, enable: bool) -> Result<bool> {
+        if self.get_enabled()? == enable {
+            return Ok(false);
+        }
+        self.enable(enable)?;
+        Ok(true)
+    }
+
+    /// Get the currently configured period in microseconds, truncated
+    pub fn get_period_us(&self) -> Result<u64> {
+        Ok(self.get_period_ns()? / 1_000)
+    }
+
+    /// Set the period in microseconds
+    pub fn set_period_us(&self, period_us: u64) -> Result<()> {
+        self.set_period_ns(units_to_ns(period_us, 1_000)?)
+    }
+
+    /// Get the currently configured period in milliseconds, truncated
+    pub fn get_period_ms(&self) -> Result<u64> {
+        Ok(self.get_period_ns()? / 1_000_000)
+    }
+
+    /// Set the period in milliseconds
+    pub fn set_period_ms(&self, period_ms: u64) -> Result<()> {
+        self.set_period_ns(units_to_ns(period_ms, 1_000_000)?)
+    }
+
+    /// Get the currently configured duty_cycle in microseconds, truncated
+    pub fn get_duty_cycle_us(&self) -> Result<u64> {
+        Ok(self.get_duty_cycle_ns()? / 1_000)
+    }
+
+    /// Set the duty_cycle in microseconds
+    pub fn set_duty_cycle_us(&self, duty_cycle_us: u64) -> Result<()> {
+        self.set_duty_cycle_ns(units_to_ns(duty_cycle_us, 1_000)?)
+    }
+
+    /// Get the currently configured duty_cycle in milliseconds, truncated
+    pub fn get_duty_cycle_ms(&self) -> Result<u64> {
+        Ok(self.get_duty_cycle_ns()? / 1_000_000)
+    }
+
+    /// Set the duty_cycle in milliseconds
+    pub fn set_duty_cycle_ms(&self, duty_cycle_ms: u64) -> Result<()> {
+        self.set_duty_cycle_ns(units_to_ns(duty_cycle_ms, 1_000_000)?)
+    }
+
+    /// The period of the PWM signal as a `Duration`
+    pub fn get_period(&self) -> Result<Duration> {
+        Ok(Duration::from_nanos(self.get_period_ns()?))
+    }
+
+    /// Set the period from a `Duration`
+    ///
+    /// Returns `Error::OutOfRange` if `period` has more nanoseconds than
+    /// the attribute can hold.
+    pub fn set_period(&self, period: Duration) -> Result<()> {
+        self.set_period_ns(duration_to_ns(period)?)
+    }
+
+    /// The active time of the PWM signal as a `Duration`
+    pub fn get_duty_cycle_duration(&self) -> Result<Duration> {
+        Ok(Duration::from_nanos(self.get_duty_cycle_ns()?))
+    }
+
+    /// Set the active time from a `Duration`
+    ///
+    /// Returns `Error::OutOfRange` if `duty_cycle` has more nanoseconds
+    /// than the attribute can hold.
+    pub fn set_duty_cycle_duration(&self, duty_cycle: Duration) -> Result<()> {
+        self.set_duty_cycle_ns(duration_to_ns(duty_cycle)?)
+    }
+
+    /// Get the period exactly as exposed by the kernel, trimmed but unparsed
+    pub fn get_period_raw(&self) -> Result<String> {
+        Ok(trim_value(&self.pwm.read_attr("period")?).to_string())
+    }
+
+    /// Get the duty_cycle exactly as exposed by the kernel, trimmed but unparsed
+    pub fn get_duty_cycle_raw(&self) -> Result<String> {
+        Ok(trim_value(&self.pwm.read_attr("duty_cycle")?).to_string())
+    }
+
+    /// Set the period rounded to the nearest multiple of `resolution_ns`
+    ///
+    /// Returns the period in nanoseconds that was actually written, making
+    /// any quantization by the hardware explicit to the caller.
+    pub fn set_period_aligned(&self, d: Duration, resolution_ns: u64) -> Result<u64> {
+        let period_ns = align_ns(d.as_nanos(), resolution_ns)?;
+        self.set_period_ns(period_ns)?;
+        Ok(period_ns)
+    }
+
+    /// Set the duty cycle rounded to the nearest multiple of `resolution_ns`
+    ///
+    /// Like `set_period_aligned`, this returns the duty cycle in nanoseconds
+    /// that was actually written.
+    pub fn set_duty_cycle_ns_aligned(&self, ns: u64, resolution_ns: u64) -> Result<u64> {
+        let duty_cycle_ns = align_ns(u128::from(ns), resolution_ns)?;
+        self.set_duty_cycle_ns(duty_cycle_ns)?;
+        Ok(duty_cycle_ns)
+    }
+
+    /// Start a fluent reconfiguration of this PWM
+    ///
+    /// Nothing is written until `apply` is called on the returned value.
+    pub fn config(&self) -> PwmConfigurator<'_> {
+        PwmConfigurator {
+            pwm: self,
+            period_ns: None,
+            duty_cycle_ns: None,
+            polarity: None,
+            enabled: None,
+        }
+    }
+
+    /// Set the period and duty cycle without ever passing through an
+    /// intermediate state where the duty cycle exceeds the period
+    ///
+    /// The kernel rejects a duty cycle larger than the current period, so
+    /// the duty cycle is written first if it fits in the current period and
+    /// the period is written first otherwise.
+    fn set_period_and_duty_ns(&self, period_ns: u64, duty_cycle_ns: u64) -> Result<()> {
+        if duty_cycle_ns <= self.get_period_ns()? {
+            self.write_duty_cycle_ns(duty_cycle_ns)?;
+            self.set_period_ns(period_ns)
+        } else {
+            self.set_period_ns(period_ns)?;
+            self.write_duty_cycle_ns(duty_cycle_ns)
+        }
+    }
+
+    /// Change the period of a running output without a malformed pulse
+    ///
+    /// Writing a new period while enabled can produce a half-formed pulse
+    /// on some hardware, so the duty cycle is first set to 0, then the new
+    /// period is written, then the new duty cycle.  Only if the driver still
+    /// rejects the period change is the output briefly disabled around it.
+    /// If the period is still rejected, the previous duty cycle is written
+    /// back and the output re-enabled if it was, on a best-effort basis,
+    /// before the error of the first period write is returned.
+    pub fn set_period_staged(&self, new_period_ns: u64, new_duty_ns: u64) -> Result<()> {
+        check_duty_cycle_ns(new_duty_ns, new_period_ns)?;
+        let old_duty_ns = self.get_duty_cycle_ns()?;
+        self.write_duty_cycle_ns(0)?;
+        let err = match self.set_period_ns(new_period_ns) {
+            Ok(()) => return self.write_duty_cycle_ns(new_duty_ns),
+            Err(err) => err,
+        };
+        let enabled = match self.get_enabled() {
+            Ok(enabled) => enabled,
+            Err(_) => {
+                self.restore_staged(old_duty_ns, false);
+                return Err(err);
+            }
+        };
+        if !enabled {
+            self.restore_staged(old_duty_ns, false);
+            return Err(err);
+        }
+        if self.enable(false).is_err() || self.set_period_ns(new_period_ns).is_err() {
+            self.restore_staged(old_duty_ns, true);
+            return Err(err);
+        }
+        self.write_duty_cycle_ns(new_duty_ns)?;
+        self.enable(true)
+    }
+
+    /// Undo a failed `set_period_staged`, ignoring any further errors
+    ///
+    /// The old period is still in place, so the old duty cycle fits it.
+    fn restore_staged(&self, old_duty_ns: u64, enable: bool) {
+        let _ = self.write_duty_cycle_ns(old_duty_ns);
+        if enable {
+            let _ = self.enable(true);
+        }
+    }
+
+    /// Change the period while keeping the duty cycle fraction constant
+    ///
+    /// The current duty cycle is scaled by `new_period_ns / old_period_ns`
+    /// and both values are written in an order the kernel accepts.  Returns
+    /// `Error::PeriodNotSet` if the current period is 0.
+    pub fn rescale_to_period(&self, new_period_ns: u64) -> Result<()> {
+        let old_period_ns = self.get_period_ns()?;
+        check_period_set(old_period_ns)?;
+        let duty_cycle_ns = self.get_duty_cycle_ns()?;
+        let new_duty_cycle_ns =
+            u128::from(duty_cycle_ns) * u128::from(new_period_ns) / u128::from(old_period_ns);
+        self.set_period_and_duty_ns(
+            new_period_ns,
+            u64::try_from(new_duty_cycle_ns).unwrap_or(u64::MAX),
+        )
+    }
+
+    /// Sweep the frequency from `from_hz` to `to_hz` in `steps` steps
+    ///
+    /// Each frequency is held for `dwell` before moving on, and the duty
+    /// cycle is rescaled on every step so its fraction of the period stays
+    /// what it was when the sweep started.
+    pub fn frequency_sweep(
+        &self,
+        from_hz: f64,
+        to_hz: f64,
+        steps: usize,
+        dwell: Duration,
+        scale: SweepScale,
+    ) -> Result<()> {
+        // validate both ends before touching the hardware
+        hz_to_period_ns(from_hz)?;
+        hz_to_period_ns(to_hz)?;
+        let period_ns = self.get_period_ns()?;
+        let fraction = if period_ns == 0 {
+            0.0
+        } else {
+            self.get_duty_cycle_ns()? as f64 / period_ns as f64
+        };
+        for step in 0..steps {
+            let period_ns = hz_to_period_ns(scale.frequency(from_hz, to_hz, step, steps))?;
+            let duty_cycle_ns = fraction_of_period(period_ns, fraction)?;
+            self.set_period_and_duty_ns(period_ns, duty_cycle_ns)?;
+            thread::sleep(dwell);
+        }
+        Ok(())
+    }
+
+    /// Set the output to `frequency_hz` with a duty cycle of `duty_percent`
+    ///
+    /// `duty_percent` is from 0 to 100 and `frequency_hz` must be positive;
+    /// a duty cycle outside that range returns `Error::OutOfRange`.  The
+    /// period and duty cycle are written in the safe order.
+    pub fn set_output(&self, frequency_hz: f64, duty_percent: f32) -> Result<()> {
+        check_duty_percent(duty_percent)?;
+        let period_ns = hz_to_period_ns(frequency_hz)?;
+        let duty_cycle_ns = f64_to_ns(period_ns as f64 * f64::from(duty_percent) / 100.0)?;
+        check_duty_cycle_ns(duty_cycle_ns, period_ns)?;
+        self.set_period_and_duty_ns(period_ns, duty_cycle_ns)
+    }
+
+    /// Arm an RC ESC
+    ///
+    /// The period is set to 50 Hz and the output enabled with the minimum
+    /// throttle pulse of 1000 us, which is then held for `ESC_ARM_DURATION`
+    /// before returning.  Use `set_throttle` afterwards.
+    pub fn arm_esc(&self) -> Result<()> {
+        self.set_period_and_duty_ns(ESC_PERIOD_NS, ESC_MIN_PULSE_NS)?;
+        self.enable(true)?;
+        thread::sleep(ESC_ARM_DURATION);
+        Ok(())
+    }
+
+    /// Set the throttle of an armed RC ESC
+    ///
+    /// `throttle` from 0.0 to 1.0 is mapped linearly onto pulses from 1000 us
+    /// to 2000 us.  Values outside that range are rejected.
+    pub fn set_throttle(&self, throttle: f32) -> Result<()> {
+        self.set_duty_cycle_ns(throttle_to_pulse_ns(throttle)?)
+    }
+
+    /// Get the polarity the PWM would have if inverted, without writing it
+    pub fn would_invert(&self) -> Result<Polarity> {
+        Ok(self.get_polarity()?.inverse())
+    }
+
+    /// Get the consumer label of the PWM, if the kernel exposes one
+    ///
+    /// Returns `None` when the `label` attribute does not exist.
+    pub fn label(&self) -> Result<Option<String>> {
+        match self.pwm.read_attr("label") {
+            Ok(s) => Ok(Some(trim_value(&s).to_string())),
+            Err(Error::Io { ref source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Set the consumer label of the PWM
+    ///
+    /// This fails if the kernel does not expose a writable `label`.
+    pub fn set_label(&self, label: &str) -> Result<()> {
+        self.pwm.write_attr("label", label.as_bytes())
+    }
+
+    #[inline]
+    fn write_polarity(&self, polarity: &Polarity) -> Result<()> {
+        let value = polarity_value(*polarity, self.pwm.inverse_spelling);
+        self.pwm.write_attr("polarity", value.as_bytes())
+    }
+
+    /// Set the polarity of the PWM signal
+    ///
+    /// Some drivers refuse to change polarity while the output is enabled.
+    /// If that is known or detected (see `polarity_requires_disable`), the
+    /// output is disabled around the write and re-enabled afterwards;
+    /// otherwise the output is left running.
+    pub fn set_polarity(&self, polarity: Polarity) -> Result<()> {
+        if !self.get_enabled()? {
+            return self.write_polarity(&polarity);
+        }
+        let requires_disable = *self.pwm.polarity_requires_disable.lock().unwrap();
+        if requires_disable != Some(true) {
+            match self.write_polarity(&polarity) {
+                Err(Error::Io { ref source, .. })
+                    if source.kind() == io::ErrorKind::ResourceBusy =>
+                {
+                    *self.pwm.polarity_requires_disable.lock().unwrap() = Some(true);
+                }
+                result => return result,
+            }
+        }
+        self.enable(false)?;
+        self.write_polarity(&polarity)?;
+        self.enable(true)
+    }
+
+    /// Determine whether this driver refuses polarity writes while enabled
+    ///
+    /// If the output is enabled, the current polarity is written back and
+    /// an EBUSY failure is taken to mean a disable is required.  Some drivers
+    /// only reject actual changes, so `set_polarity` also records an EBUSY
+    /// seen on a real change.  The result is cached once probed.  While the
+    /// output is disabled no disable is required and `false` is returned
+    /// without caching.
+    pub fn polarity_requires_disable(&self) -> Result<bool> {
+        if let Some(requires_disable) = *self.pwm.polarity_requires_disable.lock().unwrap() {
+            return Ok(requires_disable);
+        }
+        if !self.get_enabled()? {
+            return Ok(false);
+        }
+        let requires_disable = match self.write_polarity(&self.get_polarity()?) {
+            Ok(()) => false,
+            Err(Error::Io { ref source, .. }) if source.kind() == io::ErrorKind::ResourceBusy => {
+                true
+            }
+            Err(e) => return Err(e),
+        };
+        *self.pwm.polarity_requires_disable.lock().unwrap() = Some(requires_disable);
+        Ok(requires_disable)
+    }
+
+    /// Set the polarity of the PWM signal and confirm it took effect
+    ///
+    /// Some drivers silently ignore a polarity they do not support, so the
+    /// polarity is read back after writing and an error is returned if it
+    /// does not match.
+    pub fn set_polarity_verified(&self, polarity: Polarity) -> Result<()> {
+        self.set_polarity(polarity)?;
+        let read_back = self.get_polarity()?;
+        if read_back == polarity {
+            return Ok(());
+        }
+        Err(Error::Unexpected(format!(
+            "Polarity write did not take effect, read back {:?}",
+            read_back
+        )))
+    }
+
+    /// Get the polarity of the PWM signal
+    pub fn get_polarity(&self) -> Result<Polarity> {
+        let s = self.pwm.read_attr("polarity")?;
+        Polarity::try_from_sysfs(&s)
+    }
+}
+
+impl<'a> PwmConfigurator<'a> {
+    /// Set the period in nanoseconds
+    pub fn period_ns(mut self, period_ns: u64) -> Self {
+        self.period_ns = Some(period_ns);
+        self
+    }
+
+    /// Set the duty cycle in nanoseconds
+    pub fn duty_ns(mut self, duty_cycle_ns: u64) -> Self {
+        self.duty_cycle_ns = Some(duty_cycle_ns);
+        self
+    }
+
+    /// Set the polarity
+    pub fn polarity(mut self, polarity: Polarity) -> Self {
+        self.polarity = Some(polarity);
+        self
+    }
+
+    /// Enable or disable the output
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = Some(enabled);
+        self
+    }
+
+    /// Write the accumulated settings
+    ///
+    /// The output is disabled first if requested, then polarity is set,
+    /// then period and duty cycle in an order the kernel accepts, and the
+    /// output is enabled last if requested.
+    pub fn apply(self) -> Result<()> {
+        if self.enabled == Some(false) {
+            self.pwm.enable(false)?;
+        }
+        if let Some(polarity) = self.polarity {
+            self.pwm.set_polarity(polarity)?;
+        }
+        match (self.period_ns, self.duty_cycle_ns) {
+            (Some(period_ns), Some(duty_cycle_ns)) => {
+                self.pwm.set_period_and_duty_ns(period_ns, duty_cycle_ns)?
+            }
+            (Some(period_ns), None) => self.pwm.set_period_ns(period_ns)?,
+            (None, Some(duty_cycle_ns)) => self.pwm.set_duty_cycle_ns(duty_cycle_ns)?,
+            (None, None) => (),
+        }
+        if self.enabled == Some(true) {
+            self.pwm.enable(true)?;
+        }
+        Ok(())
+    }
+}
+
+/// Chips are equal if they have the same number below the same root
+///
+/// Chips of a backend without a sysfs root are only equal if they share
+/// the backend itself.
+impl PartialEq for PwmChip {
+    fn eq(&self, other: &PwmChip) -> bool {
+        self.number == other.number && same_backend(&self.backend, &other.backend)
+    }
+}
+
+impl Eq for PwmChip {}
+
+impl Hash for PwmChip {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.number.hash(state);
+        self.backend.sysfs_root().hash(state);
+    }
+}
+
+/// PWMs are equal if they refer to the same channel of the same chip
+impl PartialEq for Pwm {
+    fn eq(&self, other: &Pwm) -> bool {
+        self.chip == other.chip && self.number == other.number
+    }
+}
+
+impl Eq for Pwm {}
+
+impl Hash for Pwm {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.chip.hash(state);
+        self.number.hash(state);
+    }
+}
+
+/// The flat API of older releases, where every Pwm can be configured
+#[cfg(feature = "flat-api")]
+impl Deref for Pwm {
+    type Target = ExportedPwm;
+
+    fn deref(&self) -> &ExportedPwm {
+        self.as_exported()
+    }
+}
+
+impl<'a> From<&'a Pwm> for PwmChip {
+    fn from(pwm: &'a Pwm) -> PwmChip {
+        pwm.chip.clone()
+    }
+}
+
+impl PwmGroup {
+    /// Create a group from the given PWMs
+    pub fn new(pwms: Vec<ExportedPwm>) -> PwmGroup {
+        PwmGroup { pwms }
+    }
+
+    /// The PWMs in this group
+    pub fn pwms(&self) -> &[ExportedPwm] {
+        &self.pwms
+    }
+
+    /// Enable every PWM in the group with as little skew as possible
+    ///
+    /// All of the `enable` files are opened up front and the writes are
+    /// then issued back-to-back, which minimizes software jitter between
+    /// channels.  Truly simultaneous start requires driver support and is
+    /// not something sysfs can guarantee.
+    pub fn enable_all_synchronized(&self) -> Result<()> {
+        let mut files = Vec::with_capacity(self.pwms.len());
+        for ExportedPwm { pwm } in &self.pwms {
+            let file = pwm
+                .chip
+                .backend
+                .open_write(pwm.chip.number, pwm.number, "enable")
+                .map_err(|e| pwm.map_error(e))?;
+            let value = match pwm.value_terminator {
+                Some(terminator) => vec![b'1', terminator],
+                None => vec![b'1'],
+            };
+            files.push(file.map(|(path, file)| (path, file, value)));
+        }
+        for (pwm, file) in self.pwms.iter().zip(files.iter_mut()) {
+            match *file {
+                Some((ref path, ref mut file, ref value)) => file
+                    .write_all(value)
+                    .map_err(|e| pwm.pwm.map_error(Error::io(path)(e)))?,
+                None => pwm.enable(true)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+impl embedded_hal::pwm::ErrorType for ExportedPwm {
+    type Error = Error;
+}
+
+/// Drive the PWM through the embedded-hal 1.0 interface
+///
+/// The maximum duty cycle is the current period in nanoseconds, capped to
+/// `u16::MAX`.  Periods longer than 65535 ns are therefore only addressable
+/// in steps of `period / 65535` ns, and the period must already be set (a
+/// read failure or an unset period reports a maximum of 1).  The duty cycle
+/// is scaled against the period read at the time of each call.
+#[cfg(feature = "embedded-hal")]
+impl embedded_hal::pwm::SetDutyCycle for ExportedPwm {
+    fn max_duty_cycle(&self) -> u16 {
+        self.get_period_ns().map_or(1, common::hal_max_duty_cycle)
+    }
+
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<()> {
+        let duty_ns = common::hal_duty_cycle_ns(self.get_period_ns()?, duty)?;
+        self.set_duty_cycle_ns(duty_ns)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use common::MemoryBackend;
+
+    /// An exported pin 0 of chip 0 with the given period and duty cycle
+    fn memory_pwm(period_ns: u64, duty_cycle_ns: u64) -> (Arc<MemoryBackend>, ExportedPwm) {
+        let backend = Arc::new(MemoryBackend::new());
+        backend.set_npwm(0, 2);
+        let pwm = Pwm::with_backend(backend.clone(), 0, 0)
+            .into_exported()
+            .unwrap();
+        backend.set(0, 0, "period", &period_ns.to_string());
+        backend.set(0, 0, "duty_cycle", &duty_cycle_ns.to_string());
+        (backend, pwm)
+    }
+
+    /// The `period` and `duty_cycle` writes recorded so far, in order
+    fn timing_writes(backend: &MemoryBackend) -> Vec<(String, String)> {
+        backend
+            .writes()
+            .into_iter()
+            .filter(|(_, _, attr, _)| attr == "period" || attr == "duty_cycle")
+            .map(|(_, _, attr, value)| (attr, value))
+            .collect()
+    }
+
+    /// Every write recorded so far, in order
+    pub(crate) fn all_writes(backend: &MemoryBackend) -> Vec<(String, String)> {
+        backend
+            .writes()
+            .into_iter()
+            .map(|(_, _, attr, value)| (attr, value))
+            .collect()
+    }
+
+    pub(crate) fn writes(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|&(attr, value)| (attr.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn apply_writes_period_first_when_growing() {
+        let (backend, pwm) = memory_pwm(1_000, 500);
+        let cfg = PwmConfig::builder()
+            .period_ns(4_000)
+            .duty_cycle_ns(3_000)
+            .enabled(true)
+            .build();
+        pwm.apply(&cfg).unwrap();
+        assert_eq!(
+            timing_writes(&backend),
+            writes(&[("period", "4000"), ("duty_cycle", "3000")])
+        );
+        assert_eq!(backend.get(0, 0, "enable").unwrap(), "1");
+    }
+
+    #[test]
+    fn apply_writes_duty_first_when_shrinking() {
+        let (backend, pwm) = memory_pwm(4_000, 3_000);
+        let cfg = PwmConfig::builder()
+            .period_ns(1_000)
+            .duty_cycle_ns(500)
+            .build();
+        pwm.apply(&cfg).unwrap();
+        assert_eq!(
+            timing_writes(&backend),
+            writes(&[("duty_cycle", "500"), ("period", "1000")])
+        );
+    }
+
+    #[test]
+    fn set_output_orders_writes() {
+        let (backend, pwm) = memory_pwm(4_000, 3_000);
+        pwm.set_output(1_000_000.0, 50.0).unwrap();
+        assert_eq!(
+            timing_writes(&backend),
+            writes(&[("duty_cycle", "500"), ("period", "1000")])
+        );
+        backend.clear_writes();
+        pwm.set_output(250_000.0, 75.0).unwrap();
+        assert_eq!(
+            timing_writes(&backend),
+            writes(&[("period", "4000"), ("duty_cycle", "3000")])
+        );
+    }
+
+    #[test]
+    fn set_output_rejects_out_of_range_duty() {
+        let (backend, pwm) = memory_pwm(1_000, 0);
+        assert!(matches!(
+            pwm.set_output(1_000_000.0, 101.0),
+            Err(Error::OutOfRange(_))
+        ));
+        assert!(matches!(
+            pwm.set_output(1_000_000.0, -1.0),
+            Err(Error::OutOfRange(_))
+        ));
+        // 1 us periods, where the excess rounds away in nanoseconds
+        for duty_percent in [100.04, -0.04, f32::NAN] {
+            assert!(matches!(
+                pwm.set_output(1_000_000.0, duty_percent),
+                Err(Error::OutOfRange(_))
+            ));
+        }
+        assert!(backend.writes().is_empty());
+    }
+
+    #[test]
+    fn rescale_to_period_keeps_fraction_in_safe_order() {
+        let (backend, pwm) = memory_pwm(1_000, 250);
+        pwm.rescale_to_period(8_000).unwrap();
+        assert_eq!(
+            timing_writes(&backend),
+            writes(&[("period", "8000"), ("duty_cycle", "2000")])
+        );
+        backend.clear_writes();
+        pwm.rescale_to_period(400).unwrap();
+        assert_eq!(
+            timing_writes(&backend),
+            writes(&[("duty_cycle", "100"), ("period", "400")])
+        );
+    }
+
+    #[test]
+    fn set_period_staged_zeroes_duty_first() {
+        let (backend, pwm) = memory_pwm(1_000, 500);
+        pwm.set_period_staged(4_000, 3_000).unwrap();
+        assert_eq!(
+            timing_writes(&backend),
+            writes(&[
+                ("duty_cycle", "0"),
+                ("period", "4000"),
+                ("duty_cycle", "3000")
+            ])
+        );
+        backend.clear_writes();
+        assert!(matches!(
+            pwm.set_period_staged(1_000, 2_000),
+            Err(Error::OutOfRange(_))
+        ));
+        assert!(backend.writes().is_empty());
+    }
+
+    #[test]
+    fn set_period_staged_restores_after_a_failed_period() {
+        let backend = Arc::new(FailingBackend::new("period", 1_000, 500));
+        let pwm = Pwm::with_backend(backend.clone(), 0, 0)
+            .into_exported()
+            .unwrap();
+        assert!(matches!(
+            pwm.set_period_staged(4_000, 3_000),
+            Err(Error::PermissionDenied { .. })
+        ));
+        assert_eq!(
+            all_writes(&backend.inner),
+            writes(&[("duty_cycle", "0"), ("duty_cycle", "500")])
+        );
+
+        backend.inner.clear_writes();
+        backend.inner.set(0, 0, "enable", "1");
+        assert!(matches!(
+            pwm.set_period_staged(4_000, 3_000),
+            Err(Error::PermissionDenied { .. })
+        ));
+        assert_eq!(
+            all_writes(&backend.inner),
+            writes(&[
+                ("duty_cycle", "0"),
+                ("enable", "0"),
+                ("duty_cycle", "500"),
+                ("enable", "1")
+            ])
+        );
+        assert_eq!(pwm.get_duty_cycle_ns().unwrap(), 500);
+        assert!(pwm.get_enabled().unwrap());
+    }
+
+    #[test]
+    fn apply_values_writes_only_the_latest_held_value() {
+        let (backend, pwm) = memory_pwm(1_000, 0);
+        let start = Instant::now();
+        pwm.apply_values([0.1, 0.2, 0.3, 0.4], Duration::from_millis(50))
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            all_writes(&backend),
+            writes(&[("duty_cycle", "100"), ("duty_cycle", "400")])
+        );
+    }
+
+    #[test]
+    fn arm_esc_sets_the_period_then_the_minimum_pulse() {
+        let (backend, pwm) = memory_pwm(0, 0);
+        pwm.arm_esc().unwrap();
+        assert_eq!(
+            all_writes(&backend),
+            writes(&[
+                ("period", "20000000"),
+                ("duty_cycle", "1000000"),
+                ("enable", "1")
+            ])
+        );
+    }
+
+    #[test]
+    fn set_throttle_maps_onto_the_pulse_width() {
+        let (backend, pwm) = memory_pwm(20_000_000, 1_000_000);
+        for (throttle, pulse_ns) in [(0.0, 1_000_000), (1.0, 2_000_000), (0.5, 1_500_000)] {
+            pwm.set_throttle(throttle).unwrap();
+            assert_eq!(pwm.get_duty_cycle_ns().unwrap(), pulse_ns);
+        }
+        backend.clear_writes();
+        for throttle in [-0.1, 1.1, f32::NAN] {
+            assert!(matches!(
+                pwm.set_throttle(throttle),
+                Err(Error::OutOfRange(_))
+            ));
+        }
+        assert!(backend.writes().is_empty());
+    }
+
+    #[test]
+    fn export_honours_npwm_and_seeded_values() {
+        let backend = Arc::new(MemoryBackend::new());
+        backend.set_npwm(0, 2);
+        backend.set(0, 1, "period", "20000000");
+        let pwm = Pwm::with_backend(backend.clone(), 0, 1);
+        assert!(!pwm.is_exported().unwrap());
+        let pwm = pwm.into_exported().unwrap();
+        assert_eq!(pwm.get_period_ns().unwrap(), 20_000_000);
+        assert_eq!(pwm.get_duty_cycle_ns().unwrap(), 0);
+        assert_eq!(pwm.get_polarity().unwrap(), Polarity::Normal);
+        assert!(!pwm.get_enabled().unwrap());
+        let pwm = pwm.unexport().unwrap();
+        assert!(!pwm.is_exported().unwrap());
+        assert!(backend.read(0, 1, "period").is_err());
+
+        assert!(Pwm::with_backend(backend.clone(), 0, 2).export().is_err());
+        assert!(Pwm::with_backend(backend, 1, 0).export().is_err());
+    }
+
+    #[test]
+    fn zero_period_duty_cycle_is_an_error() {
+        let (_backend, pwm) = memory_pwm(0, 0);
+        assert!(matches!(pwm.get_duty_cycle(), Err(Error::PeriodNotSet)));
+        assert!(matches!(pwm.get_frequency(), Err(Error::PeriodNotSet)));
+        assert!(matches!(pwm.get_duty_ratio(), Err(Error::PeriodNotSet)));
+        assert!(matches!(pwm.set_duty_cycle(0.5), Err(Error::PeriodNotSet)));
+        assert!(matches!(pwm.enable_checked(), Err(Error::PeriodNotSet)));
+    }
+
+    /// A fake sysfs tree with `pwmchip0` of two channels, `pwm0` exported
+    pub(crate) fn fake_tree(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("sysfs-pwm-{}-{}", name, std::process::id()));
+        let pin = root.join("pwmchip0/pwm0");
+        fs::create_dir_all(&pin).unwrap();
+        fs::write(root.join("pwmchip0/npwm"), "2\n").unwrap();
+        fs::write(root.join("pwmchip0/export"), "").unwrap();
+        fs::write(root.join("pwmchip0/unexport"), "").unwrap();
+        for (attr, value) in [
+            ("period", "0\n"),
+            ("duty_cycle", "0\n"),
+            ("enable", "0\n"),
+            ("polarity", "normal\n"),
+            ("capture", "1000 250\n"),
+        ] {
+            fs::write(pin.join(attr), value).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn chips_are_listed_below_the_root() {
+        let root = fake_tree("list");
+        assert!(PwmChip::exists_in(&root, 0));
+        assert!(!PwmChip::exists_in(&root, 1));
+        let chips = PwmChip::list_with_counts_in(&root).unwrap();
+        assert_eq!(chips, [(PwmChip::with_root(&root, 0).unwrap(), 2)]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn sysfs_backend_is_the_default() {
+        let root = fake_tree("default");
+        let pwm = Pwm::with_root(&root, 0, 0)
+            .unwrap()
+            .into_exported()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("pwmchip0/export")).unwrap(),
+            ""
+        );
+        pwm.set_period_ns(1_000).unwrap();
+        pwm.set_duty_cycle_ns(250).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("pwmchip0/pwm0/period")).unwrap(),
+            "1000"
+        );
+        assert_eq!(pwm.read_all().unwrap().duty_cycle_ns, 250);
+        let mut buf = String::new();
+        assert_eq!(
+            pwm.read_capture_into(&mut buf).unwrap(),
+            (1_000, 250).into()
+        );
+        pwm.open_duty_cycle().unwrap().write(500).unwrap();
+        assert_eq!(pwm.get_duty_cycle_ns().unwrap(), 500);
+
+        let chip = pwm.chip();
+        assert_eq!(chip.count().unwrap(), 2);
+        assert_eq!(chip.exported().unwrap(), [0]);
+        assert!(!chip.is_exported(1).unwrap());
+        assert!(chip.export(2).is_err());
+        chip.export(1).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("pwmchip0/export")).unwrap(),
+            "1"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn backend_chip_count_and_exported() {
+        let backend = Arc::new(MemoryBackend::new());
+        backend.set_npwm(0, 3);
+        let pwm = Pwm::with_backend(backend, 0, 1);
+        assert_eq!(pwm.chip().count().unwrap(), 3);
+        assert!(pwm.chip().exported().unwrap().is_empty());
+        pwm.export().unwrap();
+        assert_eq!(pwm.chip().exported().unwrap(), [1]);
+        pwm.chip().require_pin(2).unwrap();
+        assert!(pwm.chip().require_pin(3).is_err());
+    }
+
+    #[test]
+    fn equality_distinguishes_backends() {
+        let a: Arc<dyn PwmBackend> = Arc::new(MemoryBackend::new());
+        let b: Arc<dyn PwmBackend> = Arc::new(MemoryBackend::new());
+        assert_eq!(
+            Pwm::with_backend(a.clone(), 0, 0),
+            Pwm::with_backend(a.clone(), 0, 0)
+        );
+        assert_ne!(
+            Pwm::with_backend(a.clone(), 0, 0),
+            Pwm::with_backend(b, 0, 0)
+        );
+        assert_ne!(
+            Pwm::with_backend(a.clone(), 0, 0),
+            Pwm::with_backend(a.clone(), 0, 1)
+        );
+
+        let sysfs = |root: &str| -> Arc<dyn PwmBackend> { Arc::new(SysfsBackend::with_root(root)) };
+        assert_eq!(
+            PwmChip::with_backend(sysfs("/a"), 0),
+            PwmChip::with_backend(sysfs("/a"), 0)
+        );
+        assert_ne!(
+            PwmChip::with_backend(sysfs("/a"), 0),
+            PwmChip::with_backend(sysfs("/b"), 0)
+        );
+        assert_ne!(
+            PwmChip::with_backend(sysfs("/a"), 0),
+            PwmChip::with_backend(a, 0)
+        );
+
+        let set: std::collections::HashSet<_> = [
+            PwmChip::with_backend(sysfs("/a"), 0),
+            PwmChip::with_backend(sysfs("/a"), 0),
+            PwmChip::with_backend(sysfs("/a"), 1),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn raw_helpers_use_the_backend() {
+        let root = fake_tree("raw");
+        let chip = PwmChip::with_root(&root, 0).unwrap();
+        crate::raw::write_with(chip.backend(), 0, 0, "period", b"1000").unwrap();
+        assert_eq!(
+            crate::raw::parse_with::<u64>(chip.backend(), 0, 0, "period").unwrap(),
+            1_000
+        );
+        assert!(crate::raw::write_with(chip.backend(), 0, 0, "vendor", b"1").is_err());
+        assert!(!root.join("pwmchip0/pwm0/vendor").exists());
+
+        let backend = MemoryBackend::new();
+        backend.set_npwm(0, 1);
+        backend.export(0, 0).unwrap();
+        backend.set(0, 0, "vendor", "7\n");
+        assert_eq!(
+            crate::raw::parse_with::<u32>(&backend, 0, 0, "vendor").unwrap(),
+            7
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn dry_run_logs_to_the_given_logger() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let log = lines.clone();
+        let pwm = Pwm::dry_run_with_logger(0, 1, DryRunDefaults::default(), move |line| {
+            log.lock().unwrap().push(line.to_string())
+        })
+        .into_exported()
+        .unwrap();
+        pwm.set_period_ns(1_000).unwrap();
+        assert_eq!(pwm.get_period_ns().unwrap(), 1_000);
+        assert_eq!(
+            *lines.lock().unwrap(),
+            [
+                "sysfs-pwm dry-run: pwmchip0 export pwm1",
+                "sysfs-pwm dry-run: pwmchip0/pwm1/period <- \"1000\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn exported_pwm_is_lent_out_and_given_back() {
+        let backend = Arc::new(MemoryBackend::new());
+        backend.set_npwm(0, 1);
+        let pwm = Pwm::with_backend(backend.clone(), 0, 0);
+        pwm.with_exported_keep(|exported| {
+            assert_eq!((exported.chip_number(), exported.number()), (0, 0));
+            exported.set_period_ns(1_000)
+        })
+        .unwrap();
+        assert!(pwm.is_exported().unwrap());
+
+        let guard = pwm.exported().unwrap();
+        assert_eq!(guard.get_period_ns().unwrap(), 1_000);
+        drop(guard);
+        assert!(!pwm.is_exported().unwrap());
+
+        let exported = pwm.into_exported().unwrap();
+        exported.set_period_ns(1_000).unwrap();
+        exported.set_duty_cycle_ns(250).unwrap();
+        let pwm = exported.unexport().unwrap();
+        assert!(!pwm.is_exported().unwrap());
+        assert_eq!(backend.get(0, 0, "duty_cycle"), None);
+    }
+
+    #[cfg(feature = "flat-api")]
+    #[test]
+    fn flat_api_configures_a_plain_pwm() {
+        let (_backend, exported) = memory_pwm(0, 0);
+        let pwm = exported.unexport().unwrap();
+        pwm.export().unwrap();
+        pwm.set_period_ns(1_000).unwrap();
+        pwm.set_duty_cycle_ns(500).unwrap();
+        assert_eq!(pwm.get_duty_cycle_ns().unwrap(), 500);
+    }
+
+    /// A `MemoryBackend` on which writes to one attribute fail with EACCES
+    #[derive(Debug)]
+    pub(crate) struct FailingBackend {
+        pub(crate) inner: Arc<MemoryBackend>,
+        pub(crate) attr: &'static str,
+    }
+
+    impl FailingBackend {
+        /// Pin 0 of chip 0, exported with the given timing
+        pub(crate) fn new(
+            attr: &'static str,
+            period_ns: u64,
+            duty_cycle_ns: u64,
+        ) -> FailingBackend {
+            let inner = Arc::new(MemoryBackend::new());
+            inner.set_npwm(0, 1);
+            inner.export(0, 0).unwrap();
+            inner.set(0, 0, "period", &period_ns.to_string());
+            inner.set(0, 0, "duty_cycle", &duty_cycle_ns.to_string());
+            FailingBackend { inner, attr }
+        }
+    }
+
+    impl PwmBackend for FailingBackend {
+        fn read(&self, chip: u32, pin: u32, attr: &str) -> Result<String> {
+            self.inner.read(chip, pin, attr)
+        }
+
+        fn write(&self, chip: u32, pin: u32, attr: &str, value: &[u8]) -> Result<()> {
+            if attr == self.attr {
+                return Err(Error::io(attr)(io::Error::from_raw_os_error(libc::EACCES)));
+            }
+            self.inner.write(chip, pin, attr, value)
+        }
+
+        fn read_chip(&self, chip: u32, attr: &str) -> Result<String> {
+            self.inner.read_chip(chip, attr)
+        }
+
+        fn is_exported(&self, chip: u32, pin: u32) -> Result<bool> {
+            self.inner.is_exported(chip, pin)
+        }
+    }
+
+    #[test]
+    fn reset_all_exported_reports_every_failed_pin() {
+        let backend = Arc::new(FailingBackend::new("duty_cycle", 1_000, 500));
+        backend.inner.set_npwm(0, 3);
+        backend.inner.export(0, 2).unwrap();
+        let chip = PwmChip::with_backend(backend.clone(), 0);
+        match chip.reset_all_exported(false) {
+            Err(Error::ResetFailed { chip: 0, failures }) => {
+                let pins: Vec<_> = failures.iter().map(|&(pin, _)| pin).collect();
+                assert_eq!(pins, [0, 2]);
+                assert!(failures
+                    .iter()
+                    .all(|(_, e)| matches!(e, Error::PermissionDenied { .. })));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(
+            all_writes(&backend.inner),
+            writes(&[("enable", "0"), ("enable", "0")])
+        );
+    }
+
+    #[test]
+    fn io_error_mapper_sees_permission_denied() {
+        let backend = Arc::new(FailingBackend::new("duty_cycle", 1_000, 0));
+        let pwm = Pwm::with_backend(backend.clone(), 0, 0)
+            .into_exported()
+            .unwrap();
+        assert!(matches!(
+            pwm.set_duty_cycle_ns(0),
+            Err(Error::PermissionDenied { .. })
+        ));
+
+        let mut pwm = Pwm::with_backend(backend, 0, 0);
+        pwm.set_io_error_mapper(|e| match e.raw_os_error() {
+            Some(libc::EACCES) => Some(Error::Unexpected("read-only".to_owned())),
+            _ => None,
+        });
+        let pwm = pwm.into_exported().unwrap();
+        assert!(matches!(
+            pwm.set_duty_cycle_ns(0),
+            Err(Error::Unexpected(_))
+        ));
+    }
+
+    #[test]
+    fn slew_to_duty_rejects_a_bad_step() {
+        let (backend, pwm) = memory_pwm(1_000, 0);
+        for step in [-0.1, f32::NAN, f32::INFINITY] {
+            assert!(matches!(
+                pwm.slew_to_duty(0.5, step),
+                Err(Error::OutOfRange(_))
+            ));
         }
+        assert!(backend.writes().is_empty());
     }
 }
@@ -1,110 +1,574 @@
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use tokio::fs;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+#[cfg(feature = "tokio")]
+use tokio::sync::{mpsc, watch};
+#[cfg(feature = "tokio")]
+use tokio::task::JoinHandle;
+#[cfg(feature = "tokio")]
+use tokio::time;
 
 use crate::common;
-use common::{Error, Polarity, Result};
+use crate::rt::{self, File, OpenOptions, ReadExt, WriteExt};
+use common::{
+    align_ns, attr_path, capture_supported, check_duty_cycle_ns, check_duty_fraction,
+    check_duty_percent, check_period_set, check_pin, duration_to_ns, duty_fraction, f64_to_ns,
+    fraction_of_period, high_fraction, hz_to_period_ns, on_time_to_duty_ns, parse_capture,
+    parse_enabled, parse_npwm, parse_value, period_to_hz, polarity_value, reset_result, round_hz,
+    same_backend, throttle_to_pulse_ns, trim_value, units_to_ns, Capture, DryRun, DryRunDefaults,
+    DutyCycle, DutyRatio, Error, Frequency, IoErrorMapper, Polarity, PwmBackend, PwmConfig,
+    PwmState, Result, SweepScale, SysfsBackend, ESC_ARM_DURATION, ESC_MIN_PULSE_NS, ESC_PERIOD_NS,
+    POLL_INTERVAL, SYSFS_PWM_ROOT,
+};
 
 #[derive(Debug)]
 pub struct PwmAsync {
     chip: PwmChipAsync,
     number: u32,
+    polarity_requires_disable: Mutex<Option<bool>>,
+    value_terminator: Option<u8>,
+    inverse_spelling: Option<&'static str>,
+    io_error_mapper: Option<IoErrorMapper>,
 }
 
-#[derive(Debug)]
+/// A `pwmchipN` directory accessed with async I/O, see `PwmChip`
+///
+/// Chips are equal if they have the same number below the same root.
+/// Only the pin attributes of a sysfs chip are accessed with async I/O;
+/// exporting, unexporting and listing pins go through a `SysfsBackend`
+/// for the same root, like they do for `PwmChip`.
+#[derive(Debug, Clone)]
 pub struct PwmChipAsync {
     pub number: u32,
+    sysfs: SysfsBackend,
+    backend: Option<Arc<dyn PwmBackend>>,
 }
 
-#[inline]
-async fn pwm_file_write(chip: u32, pin: u32, name: &str, value: &[u8]) -> Result<()> {
-    Ok(
-        File::create(format!("/sys/class/pwm/pwmchip{chip}/pwm{pin}/{name}"))
-            .await?
-            .write_all(value)
-            .await?,
-    )
+/// Disables the PWM when dropped unless it has been disarmed
+///
+/// `drop` cannot await, so the write is done with blocking I/O.
+struct DisableOnDrop<'a> {
+    pwm: Option<&'a ExportedPwmAsync>,
+}
+
+impl Drop for DisableOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Some(pwm) = self.pwm {
+            let _ = pwm.disable_blocking();
+        }
+    }
+}
+
+/// Keeps a PwmAsync exported until closed, created by `PwmAsync::exported`
+///
+/// `drop` cannot await, so `close` should be used to unexport.  If the
+/// guard is dropped instead, the PWM is unexported with blocking I/O on a
+/// best-effort basis.  The guard dereferences to the `ExportedPwmAsync`.
+#[derive(Debug)]
+pub struct ExportGuardAsync<'a> {
+    pwm: Option<&'a PwmAsync>,
+}
+
+impl<'a> ExportGuardAsync<'a> {
+    /// Leave the PWM exported and return it
+    pub fn disarm(mut self) -> &'a ExportedPwmAsync {
+        self.pwm.take().unwrap().as_exported()
+    }
+
+    /// Unexport the PWM
+    pub async fn close(mut self) -> Result<()> {
+        self.pwm.take().unwrap().unexport().await
+    }
+}
+
+impl Deref for ExportGuardAsync<'_> {
+    type Target = ExportedPwmAsync;
+
+    fn deref(&self) -> &ExportedPwmAsync {
+        self.pwm.unwrap().as_exported()
+    }
+}
+
+impl Drop for ExportGuardAsync<'_> {
+    fn drop(&mut self) {
+        if let Some(pwm) = self.pwm {
+            let _ = pwm.unexport_blocking();
+        }
+    }
+}
+
+/// A PwmAsync which is known to be exported, created by
+/// `PwmAsync::into_exported`
+///
+/// See `ExportedPwm`; with the `flat-api` feature a `PwmAsync`
+/// dereferences to an `ExportedPwmAsync`.
+#[derive(Debug, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct ExportedPwmAsync {
+    pwm: PwmAsync,
+}
+
+/// Chips are equal if they have the same number below the same root
+///
+/// Chips of a backend without a sysfs root are only equal if they share
+/// the backend itself.
+impl PartialEq for PwmChipAsync {
+    fn eq(&self, other: &PwmChipAsync) -> bool {
+        if self.number != other.number {
+            return false;
+        }
+        match (&self.backend, &other.backend) {
+            (Some(a), Some(b)) => same_backend(a, b),
+            _ => self.sysfs_root() == other.sysfs_root(),
+        }
+    }
+}
+
+impl Eq for PwmChipAsync {}
+
+impl Hash for PwmChipAsync {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.number.hash(state);
+        self.sysfs_root().hash(state);
+    }
+}
+
+/// PWMs are equal if they refer to the same channel of the same chip
+impl PartialEq for PwmAsync {
+    fn eq(&self, other: &PwmAsync) -> bool {
+        self.chip == other.chip && self.number == other.number
+    }
+}
+
+impl Eq for PwmAsync {}
+
+impl Hash for PwmAsync {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.chip.hash(state);
+        self.number.hash(state);
+    }
+}
+
+/// The flat API of older releases, where every PwmAsync can be configured
+#[cfg(feature = "flat-api")]
+impl Deref for PwmAsync {
+    type Target = ExportedPwmAsync;
+
+    fn deref(&self) -> &ExportedPwmAsync {
+        self.as_exported()
+    }
+}
+
+/// The `duty_cycle` attribute of a PwmAsync held open for repeated writes,
+/// created by `ExportedPwmAsync::open_duty_cycle`
+///
+/// See `DutyCycleWriter`.
+#[derive(Debug)]
+pub struct DutyCycleWriterAsync<'a> {
+    pwm: &'a ExportedPwmAsync,
+    file: Option<(PathBuf, File)>,
+    buf: Vec<u8>,
+}
+
+impl DutyCycleWriterAsync<'_> {
+    /// Write the duty cycle in nanoseconds
+    ///
+    /// See `DutyCycleWriter::write`.
+    pub async fn write(&mut self, ns: u64) -> Result<()> {
+        let pwm = self.pwm;
+        let (path, file) = match self.file {
+            Some((ref path, ref mut file)) => (path, file),
+            None => return pwm.write_duty_cycle_ns(ns).await,
+        };
+        self.buf.clear();
+        self.buf.extend_from_slice(ns.to_string().as_bytes());
+        if let Some(terminator) = pwm.pwm.value_terminator {
+            self.buf.push(terminator);
+        }
+        let result = match rt::rewind(file).await {
+            Ok(_) => file.write_all(&self.buf).await,
+            Err(e) => Err(e),
+        };
+        result
+            .map_err(Error::io(path))
+            .map_err(|e| pwm.pwm.map_error(e))
+    }
+}
+
+/// Fluent reconfiguration of a PwmAsync, created by
+/// `ExportedPwmAsync::config`
+///
+/// Only the settings that were given are written when `apply` is called.
+#[derive(Debug)]
+#[must_use]
+pub struct PwmConfiguratorAsync<'a> {
+    pwm: &'a ExportedPwmAsync,
+    period_ns: Option<u64>,
+    duty_cycle_ns: Option<u64>,
+    polarity: Option<Polarity>,
+    enabled: Option<bool>,
+}
+
+/// Duty cycle setpoints for a background writer, created by
+/// `ExportedPwmAsync::coalescing_writer`
+///
+/// Only the most recent setpoint is written; values sent while a write
+/// is pending are dropped.  The background task stops once this is
+/// dropped or `close` is called.  This requires the `tokio` feature.
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub struct DutySink {
+    tx: watch::Sender<Option<u64>>,
+    task: JoinHandle<Result<()>>,
+}
+
+/// Number of unreceived captures buffered by `spawn_capture_poller`
+#[cfg(feature = "tokio")]
+const CAPTURE_POLLER_CAPACITY: usize = 16;
+
+/// Minimum time between two writes made by a `DutySink`
+#[cfg(feature = "tokio")]
+const DUTY_SINK_MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Open an existing sysfs file for writing
+///
+/// Unlike `File::create` this never creates the file.
+async fn open_write(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .await
+        .map_err(Error::io(path))
 }
 
 #[inline]
-async fn pwm_file_read(chip: u32, pin: u32, name: &str) -> Result<String> {
-    Ok(fs::read_to_string(format!("/sys/class/pwm/pwmchip{chip}/pwm{pin}/{name}")).await?)
+pub(crate) async fn pwm_file_write(
+    root: &Path,
+    chip: u32,
+    pin: u32,
+    name: &str,
+    value: &[u8],
+) -> Result<()> {
+    let path = attr_path(root, chip, pin, name);
+    let mut f = open_write(&path).await?;
+    f.write_all(value).await.map_err(Error::io(path))
 }
 
 #[inline]
-async fn pwm_file_parse<T: FromStr>(chip: u32, pin: u32, name: &str) -> Result<T> {
-    let s = pwm_file_read(chip, pin, name).await?;
-    match s.trim().parse::<T>() {
-        Ok(r) => Ok(r),
-        Err(_) => Err(Error::Unexpected(format!(
-            "Unexpeted value file contents: {:?}",
-            s
-        ))),
+pub(crate) async fn pwm_file_read(root: &Path, chip: u32, pin: u32, name: &str) -> Result<String> {
+    let path = attr_path(root, chip, pin, name);
+    rt::read_to_string(&path).await.map_err(Error::io(path))
+}
+
+/// Numbers of the `pwmchipN` entries under `root`, sorted
+async fn chip_numbers(root: &Path) -> Result<Vec<u32>> {
+    let mut numbers = Vec::new();
+    for name in rt::dir_names(root).await.map_err(Error::io(root))? {
+        if let Some(number) = name.to_str().and_then(|n| n.strip_prefix("pwmchip")) {
+            if let Ok(number) = number.parse::<u32>() {
+                numbers.push(number);
+            }
+        }
     }
+    numbers.sort_unstable();
+    Ok(numbers)
 }
 
-#[inline]
-async fn pwm_file_parse_vec<T: FromStr>(chip: u32, pin: u32, name: &str) -> Result<Vec<T>> {
-    let s = pwm_file_read(chip, pin, name).await?.trim().to_string();
-    let vec_s = s.split_whitespace().collect::<Vec<_>>();
-    let mut vec: Vec<T> = vec![];
-    for s in vec_s.iter() {
-        if let Ok(j) = s.parse::<T>() {
-            vec.push(j);
+/// Count the PWM chips present on the system
+///
+/// See `count_chips`.
+pub async fn count_chips_async() -> Result<usize> {
+    Ok(chip_numbers(Path::new(SYSFS_PWM_ROOT)).await?.len())
+}
+
+/// Open every PWM chip present on the system, sorted by number
+///
+/// See `chips`.
+pub async fn chips_async() -> Result<Vec<PwmChipAsync>> {
+    chips_with_root_async(SYSFS_PWM_ROOT).await
+}
+
+/// Open every PWM chip below `root` rather than `/sys/class/pwm`
+///
+/// See `chips_with_root`.
+pub async fn chips_with_root_async<P: AsRef<Path>>(root: P) -> Result<Vec<PwmChipAsync>> {
+    let root = root.as_ref();
+    let mut chips = Vec::new();
+    for number in chip_numbers(root).await? {
+        chips.push(PwmChipAsync::with_root(root, number).await?);
+    }
+    Ok(chips)
+}
+
+/// List every exported pin on the system as `(chip, pin)` pairs
+///
+/// See `all_exported_pins`.
+pub async fn all_exported_pins_async() -> Result<Vec<(u32, u32)>> {
+    let mut pins = Vec::new();
+    for number in chip_numbers(Path::new(SYSFS_PWM_ROOT)).await? {
+        let chip = PwmChipAsync {
+            number,
+            sysfs: SysfsBackend::new(),
+            backend: None,
+        };
+        if let Ok(exported) = chip.exported().await {
+            pins.extend(exported.into_iter().map(|pin| (number, pin)));
         }
     }
-    Ok(vec)
+    Ok(pins)
 }
 
 impl PwmChipAsync {
     pub async fn new(number: u32) -> Result<PwmChipAsync> {
-        fs::metadata(&format!("/sys/class/pwm/pwmchip{}", number)).await?;
-        Ok(PwmChipAsync { number: number })
+        PwmChipAsync::with_root(SYSFS_PWM_ROOT, number).await
     }
 
-    pub async fn count(&self) -> Result<u32> {
-        let s = fs::read_to_string(format!("/sys/class/pwm/pwmchip{}/npwm", self.number)).await?;
-        match s.trim().parse::<u32>() {
-            Ok(n) => Ok(n),
-            Err(_) => Err(Error::Unexpected(format!(
-                "Unexpected npwm contents: {:?}",
-                s
-            ))),
+    /// Open chip `number` below `root` rather than `/sys/class/pwm`
+    ///
+    /// See `PwmChip::with_root`.
+    pub async fn with_root<P: Into<PathBuf>>(root: P, number: u32) -> Result<PwmChipAsync> {
+        let chip = PwmChipAsync {
+            number,
+            sysfs: SysfsBackend::with_root(root),
+            backend: None,
+        };
+        let path = chip.path();
+        rt::metadata(&path).await.map_err(Error::io(path))?;
+        Ok(chip)
+    }
+
+    /// Use chip `number` of `backend`
+    ///
+    /// See `PwmAsync::with_backend`.  Nothing is checked, so this never
+    /// fails.
+    pub fn with_backend(backend: Arc<dyn PwmBackend>, number: u32) -> PwmChipAsync {
+        PwmChipAsync {
+            number,
+            sysfs: SysfsBackend::new(),
+            backend: Some(backend),
         }
     }
 
-    pub async fn export(&self, number: u32) -> Result<()> {
-        // only export if not already exported
-        if fs::metadata(&format!(
-            "/sys/class/pwm/pwmchip{}/pwm{}",
-            self.number, number
-        ))
-        .await
-        .is_err()
-        {
-            File::create(format!("/sys/class/pwm/pwmchip{}/export", self.number))
-                .await?
-                .write_all(number.to_string().as_bytes())
-                .await?;
+    /// The `pwmchipN` directory of this chip
+    #[inline]
+    fn path(&self) -> PathBuf {
+        self.sysfs.root().join(format!("pwmchip{}", self.number))
+    }
+
+    /// The backend for exporting, unexporting and listing pins
+    ///
+    /// This is the chip's own backend, or a `SysfsBackend` for its root.
+    fn chip_backend(&self) -> &dyn PwmBackend {
+        match self.backend {
+            Some(ref backend) => &**backend,
+            None => &self.sysfs,
+        }
+    }
+
+    /// The sysfs root accessed by this chip, `None` for a backend without
+    /// one
+    fn sysfs_root(&self) -> Option<&Path> {
+        match self.backend {
+            Some(ref backend) => backend.sysfs_root(),
+            None => Some(self.sysfs.root()),
+        }
+    }
+
+    /// Read an attribute of pin `pin` through the backend or from sysfs
+    async fn read_pin(&self, pin: u32, name: &str) -> Result<String> {
+        match self.backend {
+            Some(ref backend) => backend.read(self.number, pin, name),
+            None => pwm_file_read(self.sysfs.root(), self.number, pin, name).await,
         }
-        Ok(())
+    }
+
+    /// Write an attribute of pin `pin` through the backend or to sysfs
+    async fn write_pin(&self, pin: u32, name: &str, value: &[u8]) -> Result<()> {
+        match self.backend {
+            Some(ref backend) => backend.write(self.number, pin, name, value),
+            None => pwm_file_write(self.sysfs.root(), self.number, pin, name, value).await,
+        }
+    }
+
+    /// Whether `pwmchipN` exists
+    ///
+    /// See `PwmChip::exists`.
+    pub async fn exists_async(number: u32) -> bool {
+        PwmChipAsync::exists_in_async(SYSFS_PWM_ROOT, number).await
+    }
+
+    /// Whether `pwmchipN` exists below `root` rather than `/sys/class/pwm`
+    ///
+    /// See `PwmChip::exists_in`.
+    pub async fn exists_in_async<P: AsRef<Path>>(root: P, number: u32) -> bool {
+        rt::metadata(root.as_ref().join(format!("pwmchip{}", number)))
+            .await
+            .is_ok()
+    }
+
+    /// List every chip along with its number of channels
+    ///
+    /// Chips whose `npwm` cannot be read are skipped.
+    pub async fn list_with_counts() -> Result<Vec<(PwmChipAsync, u32)>> {
+        PwmChipAsync::list_with_counts_in(SYSFS_PWM_ROOT).await
+    }
+
+    /// List every chip below `root` along with its number of channels
+    ///
+    /// See `PwmChip::list_with_counts_in`.
+    pub async fn list_with_counts_in<P: AsRef<Path>>(root: P) -> Result<Vec<(PwmChipAsync, u32)>> {
+        let root = root.as_ref();
+        let mut chips = Vec::new();
+        for number in chip_numbers(root).await? {
+            let chip = PwmChipAsync {
+                number,
+                sysfs: SysfsBackend::with_root(root),
+                backend: None,
+            };
+            if let Ok(count) = chip.count().await {
+                chips.push((chip, count));
+            }
+        }
+        Ok(chips)
+    }
+
+    pub async fn count(&self) -> Result<u32> {
+        parse_npwm(&self.chip_backend().read_chip(self.number, "npwm")?)
+    }
+
+    /// Whether `number` is below the chip's `npwm` channel count
+    pub async fn valid_pin(&self, number: u32) -> Result<bool> {
+        Ok(number < self.count().await?)
+    }
+
+    /// Return an error unless `number` is a channel of this chip
+    ///
+    /// The error names the chip's actual channel count, which is more
+    /// useful than the ENOENT a later attribute access would fail with.
+    pub async fn require_pin(&self, number: u32) -> Result<()> {
+        check_pin(self.number, self.count().await?, number)
+    }
+
+    /// Export the given pin of this chip
+    ///
+    /// See `PwmChip::export`; `Error::PinBusy` is returned if another
+    /// consumer owns the pin.
+    pub async fn export(&self, number: u32) -> Result<()> {
+        self.chip_backend().export(self.number, number)
     }
 
     pub async fn unexport(&self, number: u32) -> Result<()> {
-        if fs::metadata(&format!(
-            "/sys/class/pwm/pwmchip{}/pwm{}",
-            self.number, number
-        ))
-        .await
-        .is_ok()
-        {
-            File::create(format!("/sys/class/pwm/pwmchip{}/unexport", self.number))
-                .await?
-                .write_all(number.to_string().as_bytes())
-                .await?;
+        self.chip_backend().unexport(self.number, number)
+    }
+
+    /// Disable the given pin of this chip, then unexport it
+    ///
+    /// See `PwmChip::unexport_safe`.
+    pub async fn unexport_safe(&self, number: u32) -> Result<()> {
+        match self.write_pin(number, "enable", b"0").await {
+            Ok(()) => (),
+            Err(Error::Io { ref source, .. }) if source.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
         }
-        Ok(())
+        self.unexport(number).await
+    }
+
+    /// Whether the given pin of this chip is currently exported
+    pub async fn is_exported(&self, number: u32) -> Result<bool> {
+        self.chip_backend().is_exported(self.number, number)
+    }
+
+    /// Determine whether the chip driver supports capture
+    ///
+    /// See `PwmChip::supports_capture`.  If no pin is exported a scratch
+    /// pin is exported for the probe, and the read can block for about a
+    /// second on drivers with capture support.
+    pub async fn supports_capture(&self) -> Result<bool> {
+        if let Some(supported) = self.supports_capture_if_exported().await? {
+            return Ok(supported);
+        }
+        for pin in 0..self.count().await? {
+            match self.export(pin).await {
+                Ok(()) => (),
+                Err(Error::PinBusy { .. }) => continue,
+                Err(e) => return Err(e),
+            }
+            let read = self.read_pin(pin, "capture").await;
+            self.unexport(pin).await?;
+            return Ok(capture_supported(&read));
+        }
+        Err(Error::Unexpected(format!(
+            "pwmchip{} has no free pin to probe for capture support",
+            self.number
+        )))
+    }
+
+    /// Determine whether the chip driver supports capture without exporting
+    ///
+    /// See `PwmChip::supports_capture_if_exported`.
+    pub async fn supports_capture_if_exported(&self) -> Result<Option<bool>> {
+        match self.exported().await?.first() {
+            Some(&pin) => {
+                let read = self.read_pin(pin, "capture").await;
+                Ok(Some(capture_supported(&read)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Find exported pins of this chip which are not in `expected`
+    ///
+    /// See `PwmChip::orphaned_pins`.
+    pub async fn orphaned_pins(&self, expected: &[u32]) -> Result<Vec<u32>> {
+        let mut pins = self.exported().await?;
+        pins.retain(|pin| !expected.contains(pin));
+        Ok(pins)
+    }
+
+    /// Disable every exported pin and set its duty cycle to 0
+    ///
+    /// See `PwmChip::reset_all_exported`.
+    pub async fn reset_all_exported(&self, unexport: bool) -> Result<()> {
+        let mut failures = Vec::new();
+        for pin in self.exported().await? {
+            let reset: Result<()> = async {
+                self.write_pin(pin, "enable", b"0").await?;
+                self.write_pin(pin, "duty_cycle", b"0").await?;
+                if unexport {
+                    self.unexport(pin).await?;
+                }
+                Ok(())
+            }
+            .await;
+            if let Err(e) = reset {
+                failures.push((pin, e));
+            }
+        }
+        reset_result(self.number, failures)
+    }
+
+    /// Numbers of the pins of this chip which are currently exported, sorted
+    ///
+    /// For sysfs these are the `pwmN` entries of the chip directory.
+    pub async fn exported(&self) -> Result<Vec<u32>> {
+        self.chip_backend().exported(self.number)
+    }
+
+    /// Handles for every pin of this chip which is currently exported
+    ///
+    /// See `PwmChip::channels`.
+    pub async fn channels(&self) -> Result<Vec<PwmAsync>> {
+        Ok(self
+            .exported()
+            .await?
+            .into_iter()
+            .map(|number| PwmAsync::on_chip(self.clone(), number))
+            .collect())
     }
 }
 impl PwmAsync {
@@ -112,21 +576,180 @@ impl PwmAsync {
     ///
     /// This function does not export the Pwm pin
     pub async fn new(chip: u32, number: u32) -> Result<PwmAsync> {
-        let chip: PwmChipAsync = PwmChipAsync::new(chip).await?;
-        Ok(PwmAsync {
-            chip: chip,
-            number: number,
-        })
+        PwmAsync::with_root(SYSFS_PWM_ROOT, chip, number).await
+    }
+
+    /// Create a new PwmAsync for a chip below `root` rather than
+    /// `/sys/class/pwm`
+    ///
+    /// See `PwmChip::with_root`.  This function does not export the pin.
+    pub async fn with_root<P: Into<PathBuf>>(root: P, chip: u32, number: u32) -> Result<PwmAsync> {
+        let chip = PwmChipAsync::with_root(root, chip).await?;
+        Ok(PwmAsync::on_chip(chip, number))
+    }
+
+    /// Create a PwmAsync for pin `number` of an already opened chip
+    fn on_chip(chip: PwmChipAsync, number: u32) -> PwmAsync {
+        PwmAsync {
+            chip,
+            number,
+            polarity_requires_disable: Mutex::new(None),
+            value_terminator: None,
+            inverse_spelling: None,
+            io_error_mapper: None,
+        }
+    }
+
+    /// Open a pin which must already be exported
+    ///
+    /// Unlike `into_exported`, this returns an error if the pin directory
+    /// does not exist rather than exporting the pin.
+    pub async fn open_exported(chip: u32, number: u32) -> Result<ExportedPwmAsync> {
+        let pwm = PwmAsync::new(chip, number).await?;
+        let path = pwm.path();
+        rt::metadata(&path).await.map_err(Error::io(path))?;
+        Ok(ExportedPwmAsync { pwm })
+    }
+
+    /// Create a dry-run PwmAsync which never touches sysfs
+    ///
+    /// Writes are logged to stderr and remembered, reads return the last
+    /// value written or a zeroed default.  This is intended for testing
+    /// and local development on machines without PWM hardware only.
+    pub fn dry_run(chip: u32, number: u32) -> PwmAsync {
+        PwmAsync::dry_run_with(chip, number, DryRunDefaults::default())
+    }
+
+    /// Create a dry-run PwmAsync whose reads start out with the given values
+    ///
+    /// See `PwmAsync::dry_run`; this is intended for testing only.
+    pub fn dry_run_with(chip: u32, number: u32, defaults: DryRunDefaults) -> PwmAsync {
+        PwmAsync::with_backend(Arc::new(DryRun::new(defaults)), chip, number)
+    }
+
+    /// Create a dry-run PwmAsync which passes its log lines to `logger`
+    ///
+    /// Rather than going to stderr, every write, export and unexport is
+    /// described in one line given to `logger`.  See `PwmAsync::dry_run`.
+    pub fn dry_run_with_logger<F>(
+        chip: u32,
+        number: u32,
+        defaults: DryRunDefaults,
+        logger: F,
+    ) -> PwmAsync
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        PwmAsync::with_backend(
+            Arc::new(DryRun::with_logger(defaults, logger)),
+            chip,
+            number,
+        )
+    }
+
+    /// Create a PwmAsync whose attributes are accessed through `backend`
+    ///
+    /// See `PwmBackend`.  The backend is called synchronously, which is
+    /// fine for in-memory fakes but blocks the executor for anything slow.
+    pub fn with_backend(backend: Arc<dyn PwmBackend>, chip: u32, number: u32) -> PwmAsync {
+        PwmAsync::on_chip(PwmChipAsync::with_backend(backend, chip), number)
+    }
+
+    /// The `pwmN` directory of this pin
+    #[inline]
+    fn path(&self) -> PathBuf {
+        self.chip.path().join(format!("pwm{}", self.number))
+    }
+
+    #[inline]
+    async fn write_attr(&self, name: &str, value: &[u8]) -> Result<()> {
+        let terminated;
+        let value = match self.value_terminator {
+            Some(terminator) => {
+                terminated = [value, &[terminator]].concat();
+                &terminated[..]
+            }
+            None => value,
+        };
+        self.chip
+            .write_pin(self.number, name, value)
+            .await
+            .map_err(|e| self.map_error(e))
+    }
+
+    #[inline]
+    async fn read_attr(&self, name: &str) -> Result<String> {
+        self.chip
+            .read_pin(self.number, name)
+            .await
+            .map_err(|e| self.map_error(e))
+    }
+
+    #[inline]
+    async fn parse_attr<T: FromStr>(&self, name: &str) -> Result<T> {
+        parse_value(&self.read_attr(name).await?)
+    }
+
+    /// The chip this PWM belongs to
+    pub fn chip(&self) -> &PwmChipAsync {
+        &self.chip
+    }
+
+    /// The number of the chip this PWM belongs to
+    pub fn chip_number(&self) -> u32 {
+        self.chip.number
+    }
+
+    /// The channel number of this PWM within its chip
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// Set a byte to append to every value written to sysfs
+    ///
+    /// Values are written without a terminator by default.  Some drivers
+    /// insist on a trailing newline, which `Some(b'\n')` provides.
+    pub fn set_value_terminator(&mut self, terminator: Option<u8>) {
+        self.value_terminator = terminator;
+    }
+
+    /// Choose how inverse polarity is spelled when written
+    ///
+    /// See `Pwm::set_inverse_spelling`.
+    pub fn set_inverse_spelling(&mut self, spelling: Option<&'static str>) {
+        self.inverse_spelling = spelling;
+    }
+
+    /// Install a mapping from I/O errors to errors of this crate
+    ///
+    /// See `Pwm::set_io_error_mapper`.
+    pub fn set_io_error_mapper<F>(&mut self, mapper: F)
+    where
+        F: Fn(&io::Error) -> Option<Error> + Send + Sync + 'static,
+    {
+        self.io_error_mapper = Some(IoErrorMapper::new(mapper));
+    }
+
+    #[inline]
+    fn map_error(&self, err: Error) -> Error {
+        match self.io_error_mapper {
+            Some(ref mapper) => mapper.map(err),
+            None => err,
+        }
     }
 
     /// Run a closure with the GPIO exported
+    ///
+    /// The closure is passed this PWM as an `ExportedPwmAsync`.  It is
+    /// unexported afterwards, and if the closure succeeds but unexporting
+    /// fails, the unexport error is returned.
     #[inline]
     pub async fn with_exported<F>(&self, closure: F) -> Result<()>
     where
-        F: AsyncFnOnce() -> Result<()>,
+        F: AsyncFnOnce(&ExportedPwmAsync) -> Result<()>,
     {
         self.export().await?;
-        let y = closure().await;
+        let y = closure(self.as_exported()).await;
         match y {
             Ok(()) => self.unexport().await,
             Err(e) => match self.unexport().await {
@@ -139,122 +762,1364 @@ impl PwmAsync {
         }
     }
 
+    /// Run a closure with the PWM exported, leaving it exported on success
+    ///
+    /// As with `with_exported` the PWM is unexported again if the closure
+    /// fails, but on success it stays exported for later use.
+    #[inline]
+    pub async fn with_exported_keep<F>(&self, closure: F) -> Result<()>
+    where
+        F: AsyncFnOnce(&ExportedPwmAsync) -> Result<()>,
+    {
+        self.export().await?;
+        match closure(self.as_exported()).await {
+            Ok(()) => Ok(()),
+            Err(e) => match self.unexport().await {
+                Ok(()) => Err(e),
+                Err(ue) => Err(Error::Unexpected(format!(
+                    "Failed unexporting due to:\n{}\nwhile handling:\n{}",
+                    ue, e
+                ))),
+            },
+        }
+    }
+
     /// Export the Pwm for use
     pub async fn export(&self) -> Result<()> {
         self.chip.export(self.number).await
     }
 
+    /// Export the PWM and wait for its attributes to appear
+    ///
+    /// See `Pwm::export_and_wait`.
+    pub async fn export_and_wait(&self, timeout: Duration) -> Result<()> {
+        self.export().await?;
+        let start = Instant::now();
+        loop {
+            if self.read_attr("period").await.is_ok() {
+                return Ok(());
+            }
+            let waited = start.elapsed();
+            if waited >= timeout {
+                return Err(Error::Timeout {
+                    operation: format!(
+                        "waiting for pwmchip{}/pwm{} to appear",
+                        self.chip.number, self.number
+                    ),
+                    waited,
+                });
+            }
+            rt::sleep(POLL_INTERVAL.min(timeout - waited)).await;
+        }
+    }
+
     /// Unexport the PWM
     pub async fn unexport(&self) -> Result<()> {
         self.chip.unexport(self.number).await
     }
 
-    /// Enable/Disable the PWM Signal
-    pub async fn enable(&self, enable: bool) -> Result<()> {
-        pwm_file_write(
-            self.chip.number,
-            self.number,
-            "enable",
-            &(enable as u8).to_string().as_bytes(),
-        )
-        .await
+    /// Disable the PWM, then unexport it
+    ///
+    /// See `PwmChip::unexport_safe`.
+    pub async fn unexport_safe(&self) -> Result<()> {
+        match self.as_exported().enable(false).await {
+            Ok(()) => (),
+            Err(Error::Io { ref source, .. }) if source.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        self.unexport().await
     }
 
-    /// Query the state of enable for a given PWM pin
-    pub async fn get_enabled(&self) -> Result<bool> {
-        Ok(
-            match pwm_file_read(self.chip.number, self.number, "enable")
-                .await?
-                .trim()
-            {
-                "1" => true,
-                "0" => false,
-                _ => panic!("enable != 1|0 should be unreachable"),
-            },
-        )
+    /// Whether the PWM is currently exported
+    ///
+    /// See `Pwm::is_exported`.
+    pub async fn is_exported(&self) -> Result<bool> {
+        self.chip.is_exported(self.number).await
     }
 
-    /// Get the currently configured duty_cycle in nanoseconds
-    pub async fn get_duty_cycle_ns(&self) -> Result<u32> {
-        pwm_file_parse::<u32>(self.chip.number, self.number, "duty_cycle").await
+    /// Unexport the PWM without awaiting, for use from `Drop`
+    fn unexport_blocking(&self) -> Result<()> {
+        self.chip
+            .chip_backend()
+            .unexport(self.chip.number, self.number)
     }
 
-    /// Get the capture
-    pub async fn get_capture(&self) -> Result<(u32, u32)> {
-        let t = pwm_file_parse_vec::<u32>(self.chip.number, self.number, "capture").await?;
-        if t.len() == 2 {
-            Ok((t[0], t[1]))
-        } else {
-            Err(Error::Unexpected(format!("Failed exporting")))
-        }
+    /// Export the PWM, turning it into an `ExportedPwmAsync`
+    pub async fn into_exported(self) -> Result<ExportedPwmAsync> {
+        self.export().await?;
+        Ok(ExportedPwmAsync { pwm: self })
     }
 
-    /// The active time of the PWM signal
+    /// View this PwmAsync as exported without checking
     ///
-    /// Value is in nanoseconds and must be less than the period.
-    pub async fn set_duty_cycle_ns(&self, duty_cycle_ns: u32) -> Result<()> {
-        pwm_file_write(
-            self.chip.number,
-            self.number,
-            "duty_cycle",
-            &duty_cycle_ns.to_string().as_bytes(),
-        )
-        .await
+    /// See `Pwm::as_exported`.
+    fn as_exported(&self) -> &ExportedPwmAsync {
+        // SAFETY: `ExportedPwmAsync` is a `repr(transparent)` wrapper of `PwmAsync`
+        unsafe { &*(self as *const PwmAsync as *const ExportedPwmAsync) }
+    }
+
+    /// Export the PWM until the returned guard is closed or dropped
+    ///
+    /// See `ExportGuardAsync`.
+    pub async fn exported(&self) -> Result<ExportGuardAsync<'_>> {
+        self.export().await?;
+        Ok(ExportGuardAsync { pwm: Some(self) })
+    }
+}
+
+impl ExportedPwmAsync {
+    /// Unexport the PWM and return the plain handle
+    ///
+    /// See `ExportedPwm::unexport`.
+    pub async fn unexport(self) -> Result<PwmAsync> {
+        self.pwm.unexport().await?;
+        Ok(self.pwm)
+    }
+
+    /// The chip this PWM belongs to
+    pub fn chip(&self) -> &PwmChipAsync {
+        &self.pwm.chip
+    }
+
+    /// The number of the chip this PWM belongs to
+    pub fn chip_number(&self) -> u32 {
+        self.pwm.chip.number
+    }
+
+    /// The channel number of this PWM within its chip
+    pub fn number(&self) -> u32 {
+        self.pwm.number
+    }
+
+    /// Run a closure, restoring the prior state of the PWM if it fails
+    ///
+    /// Period, duty cycle, polarity and enable are read before running the
+    /// closure.  If the closure returns an error they are written back in a
+    /// safe order before the original error is returned.
+    pub async fn transaction<F>(&self, f: F) -> Result<()>
+    where
+        F: AsyncFnOnce(&ExportedPwmAsync) -> Result<()>,
+    {
+        let period_ns = self.get_period_ns().await?;
+        let duty_cycle_ns = self.get_duty_cycle_ns().await?;
+        let polarity = self.get_polarity().await?;
+        let enabled = self.get_enabled().await?;
+        match f(self).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let restored = match self.enable(false).await {
+                    Ok(()) => {
+                        self.config()
+                            .polarity(polarity)
+                            .period_ns(period_ns)
+                            .duty_ns(duty_cycle_ns)
+                            .enabled(enabled)
+                            .apply()
+                            .await
+                    }
+                    Err(re) => Err(re),
+                };
+                match restored {
+                    Ok(()) => Err(e),
+                    Err(re) => Err(Error::Unexpected(format!(
+                        "Failed restoring state due to:\n{}\nwhile handling:\n{}",
+                        re, e
+                    ))),
+                }
+            }
+        }
+    }
+
+    /// Enable/Disable the PWM Signal
+    pub async fn enable(&self, enable: bool) -> Result<()> {
+        self.pwm
+            .write_attr("enable", (enable as u8).to_string().as_bytes())
+            .await
+    }
+
+    /// Enable the PWM after checking that its configuration makes sense
+    ///
+    /// Nothing is enabled if the period is 0, which returns
+    /// `Error::PeriodNotSet`, or if the duty cycle exceeds the period, which
+    /// returns `Error::OutOfRange`.  Use `enable` to skip the check.
+    pub async fn enable_checked(&self) -> Result<()> {
+        let period_ns = self.get_period_ns().await?;
+        let duty_cycle_ns = self.get_duty_cycle_ns().await?;
+        check_period_set(period_ns)?;
+        check_duty_cycle_ns(duty_cycle_ns, period_ns)?;
+        self.enable(true).await
+    }
+
+    /// Enable the PWM for `duration`, then disable it again
+    ///
+    /// The output is also disabled if the returned future is dropped while
+    /// sleeping, e.g. when it loses a `select!` or times out.
+    pub async fn pulse_for(&self, duration: Duration) -> Result<()> {
+        self.enable(true).await?;
+        let mut guard = DisableOnDrop { pwm: Some(self) };
+        rt::sleep(duration).await;
+        guard.pwm = None;
+        self.enable(false).await
+    }
+
+    /// Disable the PWM without awaiting, for use from `Drop`
+    fn disable_blocking(&self) -> Result<()> {
+        let mut value = b"0".to_vec();
+        value.extend(self.pwm.value_terminator);
+        match self.pwm.chip.backend {
+            Some(ref backend) => {
+                backend.write(self.pwm.chip.number, self.pwm.number, "enable", &value)
+            }
+            None => {
+                let path = self.pwm.path().join("enable");
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .truncate(true)
+                    .open(&path)
+                    .and_then(|mut f| std::io::Write::write_all(&mut f, &value))
+                    .map_err(Error::io(path))
+            }
+        }
+    }
+
+    /// Enable/Disable the PWM Signal and wait for the change to take effect
+    ///
+    /// After writing, `get_enabled` is polled until it reflects `enable`.
+    /// If it does not within `timeout`, `Error::Timeout` is returned.
+    pub async fn enable_and_confirm(&self, enable: bool, timeout: Duration) -> Result<()> {
+        self.enable(enable).await?;
+        let start = Instant::now();
+        loop {
+            if self.get_enabled().await? == enable {
+                return Ok(());
+            }
+            let waited = start.elapsed();
+            if waited >= timeout {
+                return Err(Error::Timeout {
+                    operation: format!("waiting for enable to become {}", enable as u8),
+                    waited,
+                });
+            }
+            rt::sleep(POLL_INTERVAL.min(timeout - waited)).await;
+        }
+    }
+
+    /// Query the state of enable for a given PWM pin
+    ///
+    /// Contents other than the recognised on/off spellings are reported as
+    /// `Error::Unexpected` with the raw value rather than a panic.
+    pub async fn get_enabled(&self) -> Result<bool> {
+        parse_enabled(&self.pwm.read_attr("enable").await?)
+    }
+
+    /// Read period, duty cycle, polarity and enable in a single pass
+    ///
+    /// Sysfs offers no way to read several attributes atomically, so this
+    /// is a best-effort snapshot, but it keeps the reads back-to-back.
+    pub async fn read_all(&self) -> Result<PwmState> {
+        Ok(PwmState {
+            period_ns: self.get_period_ns().await?,
+            duty_cycle_ns: self.get_duty_cycle_ns().await?,
+            polarity: self.get_polarity().await?,
+            enabled: self.get_enabled().await?,
+        })
+    }
+
+    /// Snapshot the period, duty cycle, polarity and enable
+    ///
+    /// See `ExportedPwm::state`.
+    pub async fn state(&self) -> Result<PwmState> {
+        self.read_all().await
+    }
+
+    /// Read the period and duty cycle as a `(period_ns, duty_cycle_ns)` pair
+    ///
+    /// The two attributes cannot be read atomically over sysfs, so a
+    /// concurrent write may be seen half-applied.  If the duty cycle read
+    /// exceeds the period both are read once more, and an error is
+    /// returned if the pair is still inconsistent.
+    pub async fn read_consistent(&self) -> Result<(u64, u64)> {
+        for _ in 0..2 {
+            let period_ns = self.get_period_ns().await?;
+            let duty_cycle_ns = self.get_duty_cycle_ns().await?;
+            if duty_cycle_ns <= period_ns {
+                return Ok((period_ns, duty_cycle_ns));
+            }
+        }
+        Err(Error::Unexpected(format!(
+            "pwmchip{}/pwm{} reports a duty cycle larger than its period",
+            self.pwm.chip.number, self.pwm.number
+        )))
+    }
+
+    /// Write back a state previously returned by `read_all`
+    ///
+    /// The pin is exported if necessary, polarity, period and duty cycle
+    /// are written in an order the kernel accepts, and the pin is left
+    /// enabled or disabled as recorded in `state`.
+    pub async fn restore(&self, state: &PwmState) -> Result<()> {
+        self.pwm.export().await?;
+        self.config()
+            .enabled(state.enabled)
+            .polarity(state.polarity)
+            .period_ns(state.period_ns)
+            .duty_ns(state.duty_cycle_ns)
+            .apply()
+            .await
+    }
+
+    /// Write every setting in `cfg`
+    ///
+    /// See `ExportedPwm::apply` for the order of the writes.
+    pub async fn apply(&self, cfg: &PwmConfig) -> Result<()> {
+        self.config()
+            .enabled(cfg.enabled)
+            .polarity(cfg.polarity)
+            .period_ns(cfg.period_ns)
+            .duty_ns(cfg.duty_cycle_ns)
+            .apply()
+            .await
+    }
+
+    /// Get the currently configured duty_cycle in nanoseconds
+    pub async fn get_duty_cycle_ns(&self) -> Result<u64> {
+        self.pwm.parse_attr::<u64>("duty_cycle").await
+    }
+
+    /// Get the capture
+    ///
+    /// See `ExportedPwm::get_capture`.
+    pub async fn get_capture(&self) -> Result<Capture> {
+        parse_capture(&self.pwm.read_attr("capture").await?)
+    }
+
+    /// Get the capture as a `(period_ns, duty_cycle_ns)` tuple
+    #[deprecated(note = "use `get_capture`, which returns a `Capture`")]
+    pub async fn get_capture_raw(&self) -> Result<(u64, u64)> {
+        self.get_capture().await.map(<(u64, u64)>::from)
+    }
+
+    /// Poll the capture until it differs from `baseline`
+    ///
+    /// The capture is sampled every `poll` and the first value different
+    /// from `baseline` is returned.  If none is seen within `timeout`,
+    /// `Error::Timeout` is returned.
+    pub async fn wait_for_capture_change(
+        &self,
+        baseline: Capture,
+        poll: Duration,
+        timeout: Duration,
+    ) -> Result<Capture> {
+        let start = Instant::now();
+        loop {
+            let capture = self.get_capture().await?;
+            if capture != baseline {
+                return Ok(capture);
+            }
+            let waited = start.elapsed();
+            if waited >= timeout {
+                return Err(Error::Timeout {
+                    operation: "waiting for capture to change".to_string(),
+                    waited,
+                });
+            }
+            rt::sleep(poll.min(timeout - waited)).await;
+        }
+    }
+
+    /// Poll the capture in a background task
+    ///
+    /// A task is spawned which reads the capture every `interval` and sends
+    /// each result, including errors, over the returned channel.  The task
+    /// stops once the receiver is dropped.  This must be called from within
+    /// a tokio runtime, and requires the `tokio` feature.
+    #[cfg(feature = "tokio")]
+    pub fn spawn_capture_poller(
+        &self,
+        interval: Duration,
+    ) -> (JoinHandle<()>, mpsc::Receiver<Result<Capture>>) {
+        let pwm = self.detached();
+        let (tx, rx) = mpsc::channel(CAPTURE_POLLER_CAPACITY);
+        let handle = tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = tx.closed() => break,
+                }
+                let capture = pwm.get_capture().await;
+                if tx.send(capture).await.is_err() {
+                    break;
+                }
+            }
+        });
+        (handle, rx)
+    }
+
+    /// Write duty cycle setpoints from a background task
+    ///
+    /// Setpoints in nanoseconds sent to the returned `DutySink` are written
+    /// at most once every 10 ms, and only the latest one is kept when they
+    /// arrive faster than that.  This must be called from within a tokio
+    /// runtime, and requires the `tokio` feature.
+    #[cfg(feature = "tokio")]
+    pub fn coalescing_writer(&self) -> DutySink {
+        let pwm = self.detached();
+        let (tx, mut rx) = watch::channel(None);
+        let task = tokio::spawn(async move {
+            while rx.changed().await.is_ok() {
+                let duty_cycle_ns = *rx.borrow_and_update();
+                if let Some(duty_cycle_ns) = duty_cycle_ns {
+                    pwm.set_duty_cycle_ns(duty_cycle_ns).await?;
+                }
+                rt::sleep(DUTY_SINK_MIN_INTERVAL).await;
+            }
+            Ok(())
+        });
+        DutySink { tx, task }
+    }
+
+    /// A second handle on the same pin, for use by spawned tasks
+    #[cfg(feature = "tokio")]
+    fn detached(&self) -> ExportedPwmAsync {
+        let pwm = &self.pwm;
+        ExportedPwmAsync {
+            pwm: PwmAsync {
+                chip: pwm.chip.clone(),
+                number: pwm.number,
+                polarity_requires_disable: Mutex::new(
+                    *pwm.polarity_requires_disable.lock().unwrap(),
+                ),
+                value_terminator: pwm.value_terminator,
+                inverse_spelling: pwm.inverse_spelling,
+                io_error_mapper: pwm.io_error_mapper.clone(),
+            },
+        }
+    }
+
+    /// Read the capture into a caller-provided buffer
+    ///
+    /// See `ExportedPwm::read_capture_into`.
+    pub async fn read_capture_into(&self, buf: &mut String) -> Result<Capture> {
+        buf.clear();
+        match self.pwm.chip.backend {
+            Some(ref backend) => backend
+                .read_into(self.pwm.chip.number, self.pwm.number, "capture", buf)
+                .map_err(|e| self.pwm.map_error(e))?,
+            None => {
+                let path = self.pwm.path().join("capture");
+                match File::open(&path).await {
+                    Ok(mut f) => f.read_to_string(buf).await,
+                    Err(e) => Err(e),
+                }
+                .map_err(|e| self.pwm.map_error(Error::io(path)(e)))?;
+            }
+        }
+        parse_capture(buf)
+    }
+
+    #[inline]
+    async fn write_duty_cycle_ns(&self, duty_cycle_ns: u64) -> Result<()> {
+        self.pwm
+            .write_attr("duty_cycle", duty_cycle_ns.to_string().as_bytes())
+            .await
+    }
+
+    /// The active time of the PWM signal
+    ///
+    /// Value is in nanoseconds and must not exceed the period.  The period
+    /// is read first: `Error::PeriodNotSet` is returned if a non-zero duty
+    /// cycle is requested while the period is still 0, and
+    /// `Error::OutOfRange` if the duty cycle is larger than the period.
+    pub async fn set_duty_cycle_ns(&self, duty_cycle_ns: u64) -> Result<()> {
+        if duty_cycle_ns > 0 {
+            check_duty_cycle_ns(duty_cycle_ns, self.get_period_ns().await?)?;
+        }
+        self.write_duty_cycle_ns(duty_cycle_ns).await
+    }
+
+    /// Write the duty cycle in nanoseconds without reading the period
+    ///
+    /// This skips the checks made by `set_duty_cycle_ns`, saving a read;
+    /// a duty cycle the kernel rejects surfaces as `Error::Io`.
+    pub async fn set_duty_cycle_ns_unchecked(&self, duty_cycle_ns: u64) -> Result<()> {
+        self.write_duty_cycle_ns(duty_cycle_ns).await
+    }
+
+    /// Open the `duty_cycle` attribute once for fast repeated writes
+    ///
+    /// See `ExportedPwm::open_duty_cycle`.
+    pub async fn open_duty_cycle(&self) -> Result<DutyCycleWriterAsync<'_>> {
+        let file = match self.pwm.chip.backend {
+            Some(_) => None,
+            None => {
+                let path = self.pwm.path().join("duty_cycle");
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(&path)
+                    .await
+                    .map_err(Error::io(&path))
+                    .map_err(|e| self.pwm.map_error(e))?;
+                Some((path, file))
+            }
+        };
+        Ok(DutyCycleWriterAsync {
+            pwm: self,
+            file,
+            buf: Vec::new(),
+        })
     }
 
     /// Get the currently configured duty_cycle as percentage of period
+    ///
+    /// The duty cycle and period are read separately, so a concurrent change
+    /// can briefly make the duty cycle appear larger than the period.  The
+    /// result is clamped to `0.0..=1.0` so it is always a valid fraction.
+    /// Returns `Error::PeriodNotSet` if the period is still 0.
     pub async fn get_duty_cycle(&self) -> Result<f32> {
-        Ok((self.get_duty_cycle_ns().await? as f32) / (self.get_period_ns().await? as f32))
+        let duty_cycle_ns = self.get_duty_cycle_ns().await?;
+        duty_fraction(duty_cycle_ns, self.get_period_ns().await?)
     }
 
     /// The active time of the PWM signal
     ///
-    /// Value is as percentage of period.
+    /// Value is as fraction of period and must be within 0.0..=1.0;
+    /// anything else, including NaN, is rejected with `Error::OutOfRange`.
+    /// Use `set_duty` with `DutyCycle::from_fraction` to clamp instead.
+    /// Returns `Error::PeriodNotSet` if the period is still 0.
     pub async fn set_duty_cycle(&self, duty_cycle: f32) -> Result<()> {
-        self.set_duty_cycle_ns((self.get_period_ns().await? as f32 * duty_cycle).round() as u32)
+        check_duty_fraction(duty_cycle)?;
+        let period_ns = self.get_period_ns().await?;
+        check_period_set(period_ns)?;
+        self.write_duty_cycle_ns(fraction_of_period(period_ns, f64::from(duty_cycle))?)
+            .await
+    }
+
+    /// Set the duty cycle as a fraction of the current period
+    ///
+    /// Returns `Error::PeriodNotSet` if the period is still 0.
+    pub async fn set_duty(&self, duty_cycle: DutyCycle) -> Result<()> {
+        let period_ns = self.get_period_ns().await?;
+        check_period_set(period_ns)?;
+        self.write_duty_cycle_ns(duty_cycle.duty_cycle_ns(period_ns)?)
+            .await
+    }
+
+    /// Move the duty cycle towards `target` by at most `max_step_per_call`
+    ///
+    /// Both are fractions of the period as used by `set_duty_cycle`.  The
+    /// duty cycle written is returned, so this can be called once per
+    /// control loop tick until it returns `target`.
+    pub async fn slew_to_duty(&self, target: f32, max_step_per_call: f32) -> Result<f32> {
+        check_duty_fraction(target)?;
+        if !max_step_per_call.is_finite() || max_step_per_call < 0.0 {
+            return Err(Error::OutOfRange(format!(
+                "Slew step must be non-negative and finite, got {}",
+                max_step_per_call
+            )));
+        }
+        let current = self.get_duty_cycle().await?;
+        let next = if (target - current).abs() <= max_step_per_call {
+            target
+        } else {
+            current + max_step_per_call.copysign(target - current)
+        };
+        self.set_duty_cycle(next).await?;
+        Ok(next)
+    }
+
+    /// Get the fraction of the period during which the output is high
+    ///
+    /// Unlike `get_duty_cycle` this accounts for polarity: with inverse
+    /// polarity the output is high for `period - duty_cycle`.  Returns
+    /// `Error::PeriodNotSet` if the period is 0.
+    pub async fn effective_duty_cycle(&self) -> Result<f64> {
+        let period_ns = self.get_period_ns().await?;
+        let duty_cycle_ns = self.get_duty_cycle_ns().await?;
+        high_fraction(duty_cycle_ns, period_ns, self.get_polarity().await?)
+    }
+
+    /// Write a sequence of duty cycle fractions at a bounded rate
+    ///
+    /// Consecutive writes are at least `min_interval` apart.  A value which
+    /// arrives before the interval is up is held back and replaced by any
+    /// newer one, so only the latest value is written once the interval
+    /// has passed; the last held value is written after sleeping out the
+    /// rest of the interval.  Time spent producing values counts towards
+    /// the interval.  The period is read once up front, and values which
+    /// would not change the duty cycle are not written.
+    pub async fn apply_values<I>(&self, values: I, min_interval: Duration) -> Result<()>
+    where
+        I: IntoIterator<Item = f32>,
+    {
+        let period_ns = self.get_period_ns().await?;
+        check_period_set(period_ns)?;
+        let mut duty_cycle_ns = self.get_duty_cycle_ns().await?;
+        let mut last_write: Option<Instant> = None;
+        let mut pending = None;
+        for value in values {
+            check_duty_fraction(value)?;
+            let next_ns = fraction_of_period(period_ns, f64::from(value))?;
+            if last_write.is_some_and(|t| t.elapsed() < min_interval) {
+                pending = Some(next_ns);
+                continue;
+            }
+            pending = None;
+            if next_ns != duty_cycle_ns {
+                self.write_duty_cycle_ns(next_ns).await?;
+                duty_cycle_ns = next_ns;
+                last_write = Some(Instant::now());
+            }
+        }
+        match (pending, last_write) {
+            (Some(next_ns), Some(last_write)) if next_ns != duty_cycle_ns => {
+                if let Some(rest) = min_interval.checked_sub(last_write.elapsed()) {
+                    rt::sleep(rest).await;
+                }
+                self.write_duty_cycle_ns(next_ns).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Set the duty cycle to an exact fraction of the period
+    ///
+    /// The duty cycle is computed as `period * num / den` in integer
+    /// arithmetic, so e.g. 1/3 carries no floating point rounding.
+    pub async fn set_duty_ratio(&self, ratio: DutyRatio) -> Result<()> {
+        let period_ns = self.get_period_ns().await?;
+        check_period_set(period_ns)?;
+        self.write_duty_cycle_ns(ratio.duty_cycle_ns(period_ns)?)
             .await
     }
 
+    /// Set the duty cycle to `num / den` of the period
+    ///
+    /// See `ExportedPwm::set_duty_cycle_ratio`.
+    pub async fn set_duty_cycle_ratio(&self, num: u32, den: u32) -> Result<()> {
+        self.set_duty_ratio(DutyRatio { num, den }).await
+    }
+
+    /// Get the duty cycle as a fraction of the period in lowest terms
+    ///
+    /// Returns `Error::PeriodNotSet` if the period is 0.
+    pub async fn get_duty_ratio(&self) -> Result<DutyRatio> {
+        let period_ns = self.get_period_ns().await?;
+        check_period_set(period_ns)?;
+        DutyRatio::reduced(self.get_duty_cycle_ns().await?, period_ns)
+    }
+
+    /// Set how long the output is high each period, accounting for polarity
+    ///
+    /// With normal polarity the duty cycle is `on_ns`; with inverse
+    /// polarity it is `period - on_ns`.  `on_ns` must not exceed the period,
+    /// otherwise `Error::OutOfRange` is returned.
+    pub async fn set_on_time_ns(&self, on_ns: u64) -> Result<()> {
+        let period_ns = self.get_period_ns().await?;
+        let duty_cycle_ns = on_time_to_duty_ns(on_ns, period_ns, self.get_polarity().await?)?;
+        self.write_duty_cycle_ns(duty_cycle_ns).await
+    }
+
     /// Get the currently configured period in nanoseconds
-    pub async fn get_period_ns(&self) -> Result<u32> {
-        pwm_file_parse::<u32>(self.chip.number, self.number, "period").await
+    pub async fn get_period_ns(&self) -> Result<u64> {
+        self.pwm.parse_attr::<u64>("period").await
+    }
+
+    /// Set the period to that of `frequency`, rounded to whole nanoseconds
+    ///
+    /// See `ExportedPwm::set_frequency`.
+    pub async fn set_frequency<F>(&self, frequency: F) -> Result<()>
+    where
+        F: TryInto<Frequency>,
+        Error: From<F::Error>,
+    {
+        let frequency = frequency.try_into()?;
+        self.set_period_ns(hz_to_period_ns(frequency.as_hz())?)
+            .await
+    }
+
+    /// Get the frequency of the PWM signal in Hz
+    ///
+    /// Returns `Error::PeriodNotSet` if the period is 0.
+    pub async fn get_frequency(&self) -> Result<f64> {
+        period_to_hz(self.get_period_ns().await?)
+    }
+
+    /// Get the frequency of the PWM signal rounded to whole Hz
+    ///
+    /// This is meant for display, where 49.99998 Hz should read as 50 Hz.
+    /// Returns `Error::PeriodNotSet` if the period is 0.
+    pub async fn get_frequency_hz_rounded(&self) -> Result<u32> {
+        round_hz(period_to_hz(self.get_period_ns().await?)?)
     }
 
     /// The period of the PWM signal in Nanoseconds
-    pub async fn set_period_ns(&self, period_ns: u32) -> Result<()> {
-        pwm_file_write(
-            self.chip.number,
-            self.number,
-            "period",
-            &period_ns.to_string().as_bytes(),
+    pub async fn set_period_ns(&self, period_ns: u64) -> Result<()> {
+        self.pwm
+            .write_attr("period", period_ns.to_string().as_bytes())
+            .await
+    }
+
+    /// Set the period only if it differs from the current one
+    ///
+    /// See `ExportedPwm::set_period_ns_if_changed`.
+    pub async fn set_period_ns_if_changed(&self, period_ns: u64) -> Result<bool> {
+        if self.get_period_ns().await? == period_ns {
+            return Ok(false);
+        }
+        self.set_period_ns(period_ns).await?;
+        Ok(true)
+    }
+
+    /// Set the duty cycle only if it differs from the current one
+    ///
+    /// Returns whether anything was written.
+    pub async fn set_duty_cycle_ns_if_changed(&self, duty_cycle_ns: u64) -> Result<bool> {
+        if self.get_duty_cycle_ns().await? == duty_cycle_ns {
+            return Ok(false);
+        }
+        self.set_duty_cycle_ns(duty_cycle_ns).await?;
+        Ok(true)
+    }
+
+    /// Set the polarity only if it differs from the current one
+    ///
+    /// Returns whether anything was written.
+    pub async fn set_polarity_if_changed(&self, polarity: Polarity) -> Result<bool> {
+        if self.get_polarity().await? == polarity {
+            return Ok(false);
+        }
+        self.set_polarity(polarity).await?;
+        Ok(true)
+    }
+
+    /// Enable or disable the PWM only if that changes its state
+    ///
+    /// Returns whether anything was written.
+    pub async fn enable_if_changed(&self, enable: bool) -> Result<bool> {
+        if self.get_enabled().await? == enable {
+            return Ok(false);
+        }
+        self.enable(enable).await?;
+        Ok(true)
+    }
+
+    /// Get the currently configured period in microseconds, truncated
+    pub async fn get_period_us(&self) -> Result<u64> {
+        Ok(self.get_period_ns().await? / 1_000)
+    }
+
+    /// Set the period in microseconds
+    pub async fn set_period_us(&self, period_us: u64) -> Result<()> {
+        self.set_period_ns(units_to_ns(period_us, 1_000)?).await
+    }
+
+    /// Get the currently configured period in milliseconds, truncated
+    pub async fn get_period_ms(&self) -> Result<u64> {
+        Ok(self.get_period_ns().await? / 1_000_000)
+    }
+
+    /// Set the period in milliseconds
+    pub async fn set_period_ms(&self, period_ms: u64) -> Result<()> {
+        self.set_period_ns(units_to_ns(period_ms, 1_000_000)?).await
+    }
+
+    /// Get the currently configured duty_cycle in microseconds, truncated
+    pub async fn get_duty_cycle_us(&self) -> Result<u64> {
+        Ok(self.get_duty_cycle_ns().await? / 1_000)
+    }
+
+    /// Set the duty_cycle in microseconds
+    pub async fn set_duty_cycle_us(&self, duty_cycle_us: u64) -> Result<()> {
+        self.set_duty_cycle_ns(units_to_ns(duty_cycle_us, 1_000)?)
+            .await
+    }
+
+    /// Get the currently configured duty_cycle in milliseconds, truncated
+    pub async fn get_duty_cycle_ms(&self) -> Result<u64> {
+        Ok(self.get_duty_cycle_ns().await? / 1_000_000)
+    }
+
+    /// Set the duty_cycle in milliseconds
+    pub async fn set_duty_cycle_ms(&self, duty_cycle_ms: u64) -> Result<()> {
+        self.set_duty_cycle_ns(units_to_ns(duty_cycle_ms, 1_000_000)?)
+            .await
+    }
+
+    /// The period of the PWM signal as a `Duration`
+    pub async fn get_period(&self) -> Result<Duration> {
+        Ok(Duration::from_nanos(self.get_period_ns().await?))
+    }
+
+    /// Set the period from a `Duration`
+    ///
+    /// Returns `Error::OutOfRange` if `period` has more nanoseconds than
+    /// the attribute can hold.
+    pub async fn set_period(&self, period: Duration) -> Result<()> {
+        self.set_period_ns(duration_to_ns(period)?).await
+    }
+
+    /// The active time of the PWM signal as a `Duration`
+    pub async fn get_duty_cycle_duration(&self) -> Result<Duration> {
+        Ok(Duration::from_nanos(self.get_duty_cycle_ns().await?))
+    }
+
+    /// Set the active time from a `Duration`
+    ///
+    /// Returns `Error::OutOfRange` if `duty_cycle` has more nanoseconds
+    /// than the attribute can hold.
+    pub async fn set_duty_cycle_duration(&self, duty_cycle: Duration) -> Result<()> {
+        self.set_duty_cycle_ns(duration_to_ns(duty_cycle)?).await
+    }
+
+    /// Get the period exactly as exposed by the kernel, trimmed but unparsed
+    pub async fn get_period_raw(&self) -> Result<String> {
+        Ok(trim_value(&self.pwm.read_attr("period").await?).to_string())
+    }
+
+    /// Get the duty_cycle exactly as exposed by the kernel, trimmed but unparsed
+    pub async fn get_duty_cycle_raw(&self) -> Result<String> {
+        Ok(trim_value(&self.pwm.read_attr("duty_cycle").await?).to_string())
+    }
+
+    /// Set the period rounded to the nearest multiple of `resolution_ns`
+    ///
+    /// Returns the period in nanoseconds that was actually written, making
+    /// any quantization by the hardware explicit to the caller.
+    pub async fn set_period_aligned(&self, d: Duration, resolution_ns: u64) -> Result<u64> {
+        let period_ns = align_ns(d.as_nanos(), resolution_ns)?;
+        self.set_period_ns(period_ns).await?;
+        Ok(period_ns)
+    }
+
+    /// Set the duty cycle rounded to the nearest multiple of `resolution_ns`
+    ///
+    /// Like `set_period_aligned`, this returns the duty cycle in nanoseconds
+    /// that was actually written.
+    pub async fn set_duty_cycle_ns_aligned(&self, ns: u64, resolution_ns: u64) -> Result<u64> {
+        let duty_cycle_ns = align_ns(u128::from(ns), resolution_ns)?;
+        self.set_duty_cycle_ns(duty_cycle_ns).await?;
+        Ok(duty_cycle_ns)
+    }
+
+    /// Start a fluent reconfiguration of this PWM
+    ///
+    /// Nothing is written until `apply` is called on the returned value.
+    pub fn config(&self) -> PwmConfiguratorAsync<'_> {
+        PwmConfiguratorAsync {
+            pwm: self,
+            period_ns: None,
+            duty_cycle_ns: None,
+            polarity: None,
+            enabled: None,
+        }
+    }
+
+    /// Set the period and duty cycle without ever passing through an
+    /// intermediate state where the duty cycle exceeds the period
+    ///
+    /// The kernel rejects a duty cycle larger than the current period, so
+    /// the duty cycle is written first if it fits in the current period and
+    /// the period is written first otherwise.
+    async fn set_period_and_duty_ns(&self, period_ns: u64, duty_cycle_ns: u64) -> Result<()> {
+        if duty_cycle_ns <= self.get_period_ns().await? {
+            self.write_duty_cycle_ns(duty_cycle_ns).await?;
+            self.set_period_ns(period_ns).await
+        } else {
+            self.set_period_ns(period_ns).await?;
+            self.write_duty_cycle_ns(duty_cycle_ns).await
+        }
+    }
+
+    /// Change the period of a running output without a malformed pulse
+    ///
+    /// Writing a new period while enabled can produce a half-formed pulse
+    /// on some hardware, so the duty cycle is first set to 0, then the new
+    /// period is written, then the new duty cycle.  Only if the driver still
+    /// rejects the period change is the output briefly disabled around it.
+    /// If the period is still rejected, the previous duty cycle is written
+    /// back and the output re-enabled if it was, on a best-effort basis,
+    /// before the error of the first period write is returned.
+    pub async fn set_period_staged(&self, new_period_ns: u64, new_duty_ns: u64) -> Result<()> {
+        check_duty_cycle_ns(new_duty_ns, new_period_ns)?;
+        let old_duty_ns = self.get_duty_cycle_ns().await?;
+        self.write_duty_cycle_ns(0).await?;
+        let err = match self.set_period_ns(new_period_ns).await {
+            Ok(()) => return self.write_duty_cycle_ns(new_duty_ns).await,
+            Err(err) => err,
+        };
+        let enabled = match self.get_enabled().await {
+            Ok(enabled) => enabled,
+            Err(_) => {
+                self.restore_staged(old_duty_ns, false).await;
+                return Err(err);
+            }
+        };
+        if !enabled {
+            self.restore_staged(old_duty_ns, false).await;
+            return Err(err);
+        }
+        if self.enable(false).await.is_err() || self.set_period_ns(new_period_ns).await.is_err() {
+            self.restore_staged(old_duty_ns, true).await;
+            return Err(err);
+        }
+        self.write_duty_cycle_ns(new_duty_ns).await?;
+        self.enable(true).await
+    }
+
+    /// Undo a failed `set_period_staged`, ignoring any further errors
+    ///
+    /// The old period is still in place, so the old duty cycle fits it.
+    async fn restore_staged(&self, old_duty_ns: u64, enable: bool) {
+        let _ = self.write_duty_cycle_ns(old_duty_ns).await;
+        if enable {
+            let _ = self.enable(true).await;
+        }
+    }
+
+    /// Change the period while keeping the duty cycle fraction constant
+    ///
+    /// The current duty cycle is scaled by `new_period_ns / old_period_ns`
+    /// and both values are written in an order the kernel accepts.  Returns
+    /// `Error::PeriodNotSet` if the current period is 0.
+    pub async fn rescale_to_period(&self, new_period_ns: u64) -> Result<()> {
+        let old_period_ns = self.get_period_ns().await?;
+        check_period_set(old_period_ns)?;
+        let duty_cycle_ns = self.get_duty_cycle_ns().await?;
+        let new_duty_cycle_ns =
+            u128::from(duty_cycle_ns) * u128::from(new_period_ns) / u128::from(old_period_ns);
+        self.set_period_and_duty_ns(
+            new_period_ns,
+            u64::try_from(new_duty_cycle_ns).unwrap_or(u64::MAX),
         )
         .await
     }
 
+    /// Sweep the frequency from `from_hz` to `to_hz` in `steps` steps
+    ///
+    /// Each frequency is held for `dwell` before moving on, and the duty
+    /// cycle is rescaled on every step so its fraction of the period stays
+    /// what it was when the sweep started.
+    pub async fn frequency_sweep(
+        &self,
+        from_hz: f64,
+        to_hz: f64,
+        steps: usize,
+        dwell: Duration,
+        scale: SweepScale,
+    ) -> Result<()> {
+        // validate both ends before touching the hardware
+        hz_to_period_ns(from_hz)?;
+        hz_to_period_ns(to_hz)?;
+        let period_ns = self.get_period_ns().await?;
+        let fraction = if period_ns == 0 {
+            0.0
+        } else {
+            self.get_duty_cycle_ns().await? as f64 / period_ns as f64
+        };
+        for step in 0..steps {
+            let period_ns = hz_to_period_ns(scale.frequency(from_hz, to_hz, step, steps))?;
+            let duty_cycle_ns = fraction_of_period(period_ns, fraction)?;
+            self.set_period_and_duty_ns(period_ns, duty_cycle_ns)
+                .await?;
+            rt::sleep(dwell).await;
+        }
+        Ok(())
+    }
+
+    /// Set the output to `frequency_hz` with a duty cycle of `duty_percent`
+    ///
+    /// `duty_percent` is from 0 to 100 and `frequency_hz` must be positive;
+    /// a duty cycle outside that range returns `Error::OutOfRange`.  The
+    /// period and duty cycle are written in the safe order.
+    pub async fn set_output(&self, frequency_hz: f64, duty_percent: f32) -> Result<()> {
+        check_duty_percent(duty_percent)?;
+        let period_ns = hz_to_period_ns(frequency_hz)?;
+        let duty_cycle_ns = f64_to_ns(period_ns as f64 * f64::from(duty_percent) / 100.0)?;
+        check_duty_cycle_ns(duty_cycle_ns, period_ns)?;
+        self.set_period_and_duty_ns(period_ns, duty_cycle_ns).await
+    }
+
+    /// Arm an RC ESC
+    ///
+    /// The period is set to 50 Hz and the output enabled with the minimum
+    /// throttle pulse of 1000 us, which is then held for `ESC_ARM_DURATION`
+    /// before returning.  Use `set_throttle` afterwards.
+    pub async fn arm_esc(&self) -> Result<()> {
+        self.set_period_and_duty_ns(ESC_PERIOD_NS, ESC_MIN_PULSE_NS)
+            .await?;
+        self.enable(true).await?;
+        rt::sleep(ESC_ARM_DURATION).await;
+        Ok(())
+    }
+
+    /// Set the throttle of an armed RC ESC
+    ///
+    /// `throttle` from 0.0 to 1.0 is mapped linearly onto pulses from 1000 us
+    /// to 2000 us.  Values outside that range are rejected.
+    pub async fn set_throttle(&self, throttle: f32) -> Result<()> {
+        self.set_duty_cycle_ns(throttle_to_pulse_ns(throttle)?)
+            .await
+    }
+
+    /// Get the polarity the PWM would have if inverted, without writing it
+    pub async fn would_invert(&self) -> Result<Polarity> {
+        Ok(self.get_polarity().await?.inverse())
+    }
+
+    /// Get the consumer label of the PWM, if the kernel exposes one
+    ///
+    /// Returns `None` when the `label` attribute does not exist.
+    pub async fn label(&self) -> Result<Option<String>> {
+        match self.pwm.read_attr("label").await {
+            Ok(s) => Ok(Some(trim_value(&s).to_string())),
+            Err(Error::Io { ref source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Set the consumer label of the PWM
+    ///
+    /// This fails if the kernel does not expose a writable `label`.
+    pub async fn set_label(&self, label: &str) -> Result<()> {
+        self.pwm.write_attr("label", label.as_bytes()).await
+    }
+
+    #[inline]
+    async fn write_polarity(&self, polarity: &Polarity) -> Result<()> {
+        let value = polarity_value(*polarity, self.pwm.inverse_spelling);
+        self.pwm.write_attr("polarity", value.as_bytes()).await
+    }
+
     /// Set the polarity of the PWM signal
+    ///
+    /// See `ExportedPwm::set_polarity`; the output is only disabled around the
+    /// write if the driver requires it.
     pub async fn set_polarity(&self, polarity: Polarity) -> Result<()> {
-        pwm_file_write(
-            self.chip.number,
-            self.number,
-            "polarity",
-            match polarity {
-                Polarity::Normal => b"normal",
-                Polarity::Inverse => b"inversed",
-            },
-        )
-        .await
+        if !self.get_enabled().await? {
+            return self.write_polarity(&polarity).await;
+        }
+        let requires_disable = *self.pwm.polarity_requires_disable.lock().unwrap();
+        if requires_disable != Some(true) {
+            match self.write_polarity(&polarity).await {
+                Err(Error::Io { ref source, .. })
+                    if source.kind() == io::ErrorKind::ResourceBusy =>
+                {
+                    *self.pwm.polarity_requires_disable.lock().unwrap() = Some(true);
+                }
+                result => return result,
+            }
+        }
+        self.enable(false).await?;
+        self.write_polarity(&polarity).await?;
+        self.enable(true).await
+    }
+
+    /// Determine whether this driver refuses polarity writes while enabled
+    ///
+    /// See `ExportedPwm::polarity_requires_disable`.
+    pub async fn polarity_requires_disable(&self) -> Result<bool> {
+        if let Some(requires_disable) = *self.pwm.polarity_requires_disable.lock().unwrap() {
+            return Ok(requires_disable);
+        }
+        if !self.get_enabled().await? {
+            return Ok(false);
+        }
+        let requires_disable = match self.write_polarity(&self.get_polarity().await?).await {
+            Ok(()) => false,
+            Err(Error::Io { ref source, .. }) if source.kind() == io::ErrorKind::ResourceBusy => {
+                true
+            }
+            Err(e) => return Err(e),
+        };
+        *self.pwm.polarity_requires_disable.lock().unwrap() = Some(requires_disable);
+        Ok(requires_disable)
+    }
+
+    /// Set the polarity of the PWM signal and confirm it took effect
+    ///
+    /// Some drivers silently ignore a polarity they do not support, so the
+    /// polarity is read back after writing and an error is returned if it
+    /// does not match.
+    pub async fn set_polarity_verified(&self, polarity: Polarity) -> Result<()> {
+        self.set_polarity(polarity).await?;
+        let read_back = self.get_polarity().await?;
+        if read_back == polarity {
+            return Ok(());
+        }
+        Err(Error::Unexpected(format!(
+            "Polarity write did not take effect, read back {:?}",
+            read_back
+        )))
     }
 
     /// Get the polarity of the PWM signal
     pub async fn get_polarity(&self) -> Result<Polarity> {
-        let s = pwm_file_read(self.chip.number, self.number, "polarity").await?;
-        match s.trim() {
-            "normal" => Ok(Polarity::Normal),
-            "inversed" => Ok(Polarity::Inverse),
-            _ => Err(Error::Unexpected(format!(
-                "Unexpected polarity file contents: {:?}",
-                s
+        let s = self.pwm.read_attr("polarity").await?;
+        Polarity::try_from_sysfs(&s)
+    }
+}
+
+impl<'a> PwmConfiguratorAsync<'a> {
+    /// Set the period in nanoseconds
+    pub fn period_ns(mut self, period_ns: u64) -> Self {
+        self.period_ns = Some(period_ns);
+        self
+    }
+
+    /// Set the duty cycle in nanoseconds
+    pub fn duty_ns(mut self, duty_cycle_ns: u64) -> Self {
+        self.duty_cycle_ns = Some(duty_cycle_ns);
+        self
+    }
+
+    /// Set the polarity
+    pub fn polarity(mut self, polarity: Polarity) -> Self {
+        self.polarity = Some(polarity);
+        self
+    }
+
+    /// Enable or disable the output
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = Some(enabled);
+        self
+    }
+
+    /// Write the accumulated settings
+    ///
+    /// The output is disabled first if requested, then polarity is set,
+    /// then period and duty cycle in an order the kernel accepts, and the
+    /// output is enabled last if requested.
+    pub async fn apply(self) -> Result<()> {
+        if self.enabled == Some(false) {
+            self.pwm.enable(false).await?;
+        }
+        if let Some(polarity) = self.polarity {
+            self.pwm.set_polarity(polarity).await?;
+        }
+        match (self.period_ns, self.duty_cycle_ns) {
+            (Some(period_ns), Some(duty_cycle_ns)) => {
+                self.pwm
+                    .set_period_and_duty_ns(period_ns, duty_cycle_ns)
+                    .await?
+            }
+            (Some(period_ns), None) => self.pwm.set_period_ns(period_ns).await?,
+            (None, Some(duty_cycle_ns)) => self.pwm.set_duty_cycle_ns(duty_cycle_ns).await?,
+            (None, None) => (),
+        }
+        if self.enabled == Some(true) {
+            self.pwm.enable(true).await?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> From<&'a PwmAsync> for PwmChipAsync {
+    fn from(pwm: &'a PwmAsync) -> PwmChipAsync {
+        pwm.chip.clone()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl DutySink {
+    /// Replace the pending setpoint with `duty_cycle_ns`
+    ///
+    /// Returns an error if the background task has stopped after a failed
+    /// write; `close` returns that error.
+    pub fn send(&self, duty_cycle_ns: u64) -> Result<()> {
+        self.tx
+            .send(Some(duty_cycle_ns))
+            .map_err(|_| Error::Unexpected("The duty cycle writer has stopped".to_string()))
+    }
+
+    /// Stop accepting setpoints and wait for the background task
+    ///
+    /// The latest setpoint is written before this returns.  The error of
+    /// the first failed write, if any, is returned.
+    pub async fn close(self) -> Result<()> {
+        drop(self.tx);
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) => Err(Error::Unexpected(format!(
+                "The duty cycle writer failed: {}",
+                e
             ))),
         }
     }
 }
+
+#[cfg(feature = "embedded-hal-async")]
+impl embedded_hal::pwm::ErrorType for ExportedPwmAsync {
+    type Error = Error;
+}
+
+/// embedded-hal style duty cycle control
+///
+/// embedded-hal-async 1.0 does not define a PWM trait, so these mirror
+/// `embedded_hal::pwm::SetDutyCycle` as async inherent methods with the same
+/// scaling and u16 precision caveats as the blocking impl on `ExportedPwm`.
+#[cfg(feature = "embedded-hal-async")]
+impl ExportedPwmAsync {
+    /// The maximum duty cycle: the period in nanoseconds, capped to `u16::MAX`
+    pub async fn max_duty_cycle_u16(&self) -> u16 {
+        self.get_period_ns()
+            .await
+            .map_or(1, common::hal_max_duty_cycle)
+    }
+
+    /// Set the duty cycle as a fraction of `max_duty_cycle_u16`
+    pub async fn set_duty_cycle_u16(&self, duty: u16) -> Result<()> {
+        let duty_ns = common::hal_duty_cycle_ns(self.get_period_ns().await?, duty)?;
+        self.set_duty_cycle_ns(duty_ns).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pwm::tests::{all_writes, fake_tree, writes, FailingBackend};
+    use common::MemoryBackend;
+    use rt::block_on;
+
+    /// An exported pin 0 of chip 0 with the given period and duty cycle
+    fn memory_pwm(period_ns: u64, duty_cycle_ns: u64) -> (Arc<MemoryBackend>, ExportedPwmAsync) {
+        let backend = Arc::new(MemoryBackend::new());
+        backend.set_npwm(0, 1);
+        let pwm = block_on(PwmAsync::with_backend(backend.clone(), 0, 0).into_exported()).unwrap();
+        backend.set(0, 0, "period", &period_ns.to_string());
+        backend.set(0, 0, "duty_cycle", &duty_cycle_ns.to_string());
+        (backend, pwm)
+    }
+
+    #[test]
+    fn zero_period_duty_cycle_is_an_error() {
+        let (_backend, pwm) = memory_pwm(0, 0);
+        block_on(async {
+            assert!(matches!(
+                pwm.get_duty_cycle().await,
+                Err(Error::PeriodNotSet)
+            ));
+            assert!(matches!(
+                pwm.get_frequency().await,
+                Err(Error::PeriodNotSet)
+            ));
+            assert!(matches!(
+                pwm.set_duty_cycle(0.5).await,
+                Err(Error::PeriodNotSet)
+            ));
+        });
+    }
+
+    #[test]
+    fn set_period_staged_zeroes_duty_first() {
+        let (backend, pwm) = memory_pwm(1_000, 500);
+        block_on(pwm.set_period_staged(4_000, 3_000)).unwrap();
+        let writes: Vec<_> = backend
+            .writes()
+            .into_iter()
+            .map(|(_, _, attr, value)| (attr, value))
+            .collect();
+        assert_eq!(
+            writes,
+            [
+                ("duty_cycle", "0"),
+                ("period", "4000"),
+                ("duty_cycle", "3000")
+            ]
+            .map(|(attr, value)| (attr.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    fn set_period_staged_restores_after_a_failed_period() {
+        let backend = Arc::new(FailingBackend::new("period", 1_000, 500));
+        backend.inner.set(0, 0, "enable", "1");
+        let pwm = block_on(PwmAsync::with_backend(backend.clone(), 0, 0).into_exported()).unwrap();
+        block_on(async {
+            assert!(matches!(
+                pwm.set_period_staged(4_000, 3_000).await,
+                Err(Error::PermissionDenied { .. })
+            ));
+            assert_eq!(pwm.get_duty_cycle_ns().await.unwrap(), 500);
+            assert!(pwm.get_enabled().await.unwrap());
+        });
+        assert_eq!(
+            all_writes(&backend.inner),
+            writes(&[
+                ("duty_cycle", "0"),
+                ("enable", "0"),
+                ("duty_cycle", "500"),
+                ("enable", "1")
+            ])
+        );
+    }
+
+    #[test]
+    fn backend_chip_count_and_exported() {
+        let (backend, pwm) = memory_pwm(0, 0);
+        let pwm = block_on(async {
+            assert_eq!(pwm.chip().count().await.unwrap(), 1);
+            assert_eq!(pwm.chip().exported().await.unwrap(), [0]);
+            let pwm = pwm.unexport().await.unwrap();
+            assert!(pwm.chip().exported().await.unwrap().is_empty());
+            pwm
+        });
+        let other: Arc<dyn PwmBackend> = Arc::new(MemoryBackend::new());
+        assert_eq!(pwm, PwmAsync::with_backend(backend, 0, 0));
+        assert_ne!(pwm, PwmAsync::with_backend(other, 0, 0));
+    }
+
+    #[test]
+    fn raw_async_helpers_use_the_root() {
+        let root = std::env::temp_dir().join(format!("sysfs-pwm-raw-async-{}", std::process::id()));
+        let pin = root.join("pwmchip0/pwm0");
+        std::fs::create_dir_all(&pin).unwrap();
+        std::fs::write(pin.join("period"), "0\n").unwrap();
+        block_on(async {
+            crate::raw::write_async_with_root(&root, 0, 0, "period", b"1000")
+                .await
+                .unwrap();
+            assert_eq!(
+                crate::raw::parse_async_with_root::<u64, _>(&root, 0, 0, "period")
+                    .await
+                    .unwrap(),
+                1_000
+            );
+            assert!(
+                crate::raw::write_async_with_root(&root, 0, 0, "vendor", b"1")
+                    .await
+                    .is_err()
+            );
+        });
+        assert!(!pin.join("vendor").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn sysfs_chip_operations_use_the_root() {
+        let root = fake_tree("chip-async");
+        let chip_dir = root.join("pwmchip0");
+        block_on(async {
+            assert!(PwmChipAsync::exists_in_async(&root, 0).await);
+            assert!(!PwmChipAsync::exists_in_async(&root, 1).await);
+            let chip = PwmChipAsync::with_root(&root, 0).await.unwrap();
+            assert_eq!(
+                PwmChipAsync::list_with_counts_in(&root).await.unwrap(),
+                [(chip.clone(), 2)]
+            );
+            assert_eq!(chip.count().await.unwrap(), 2);
+            assert_eq!(chip.exported().await.unwrap(), [0]);
+            assert!(!chip.is_exported(1).await.unwrap());
+            chip.export(1).await.unwrap();
+            assert!(matches!(chip.export(2).await, Err(Error::Unexpected(_))));
+        });
+        assert_eq!(
+            std::fs::read_to_string(chip_dir.join("export")).unwrap(),
+            "1"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2016, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Low-level access to the attributes of an exported PWM pin
+//!
+//! These read and write `pwmchip{chip}/pwm{pin}/{name}` as-is, which allows
+//! vendor-specific attributes to be used.  Nothing is validated or ordered;
+//! `ExportedPwm` and `ExportedPwmAsync` remain the recommended interface.
+//! `read`, `write` and `parse` access `/sys/class/pwm`; the `_with`
+//! variants go through any backend, such as `PwmChip::backend`, and the
+//! `_with_root` async variants access the tree below another root.  The
+//! async variants require the `tokio` or `async-std` feature.
+
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+use std::path::Path;
+use std::str::FromStr;
+
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+use crate::common::SYSFS_PWM_ROOT;
+use crate::common::{parse_value, PwmBackend, Result, SysfsBackend};
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+use crate::pwm_async::{pwm_file_read, pwm_file_write};
+
+/// Read the raw contents of attribute `name` of `pwm{pin}` on `pwmchip{chip}`
+pub fn read(chip: u32, pin: u32, name: &str) -> Result<String> {
+    read_with(&SysfsBackend::new(), chip, pin, name)
+}
+
+/// Write `value` as-is to attribute `name` of `pwm{pin}` on `pwmchip{chip}`
+///
+/// The attribute is opened for writing only; it is never created.
+pub fn write(chip: u32, pin: u32, name: &str, value: &[u8]) -> Result<()> {
+    write_with(&SysfsBackend::new(), chip, pin, name, value)
+}
+
+/// Read attribute `name` and parse it as a `T`
+///
+/// Surrounding whitespace and trailing NUL bytes are ignored.
+pub fn parse<T: FromStr>(chip: u32, pin: u32, name: &str) -> Result<T> {
+    parse_with(&SysfsBackend::new(), chip, pin, name)
+}
+
+/// Read the raw contents of an attribute through `backend`
+///
+/// See `read`.
+pub fn read_with(backend: &dyn PwmBackend, chip: u32, pin: u32, name: &str) -> Result<String> {
+    backend.read(chip, pin, name)
+}
+
+/// Write `value` as-is to an attribute through `backend`
+///
+/// See `write`.
+pub fn write_with(
+    backend: &dyn PwmBackend,
+    chip: u32,
+    pin: u32,
+    name: &str,
+    value: &[u8],
+) -> Result<()> {
+    backend.write(chip, pin, name, value)
+}
+
+/// Read an attribute through `backend` and parse it as a `T`
+///
+/// See `parse`.
+pub fn parse_with<T: FromStr>(
+    backend: &dyn PwmBackend,
+    chip: u32,
+    pin: u32,
+    name: &str,
+) -> Result<T> {
+    parse_value(&read_with(backend, chip, pin, name)?)
+}
+
+/// Read the raw contents of an attribute
+///
+/// See `read`.
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub async fn read_async(chip: u32, pin: u32, name: &str) -> Result<String> {
+    read_async_with_root(SYSFS_PWM_ROOT, chip, pin, name).await
+}
+
+/// Write `value` as-is to an attribute
+///
+/// See `write`.
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub async fn write_async(chip: u32, pin: u32, name: &str, value: &[u8]) -> Result<()> {
+    write_async_with_root(SYSFS_PWM_ROOT, chip, pin, name, value).await
+}
+
+/// Read an attribute and parse it as a `T`
+///
+/// See `parse`.
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub async fn parse_async<T: FromStr>(chip: u32, pin: u32, name: &str) -> Result<T> {
+    parse_async_with_root(SYSFS_PWM_ROOT, chip, pin, name).await
+}
+
+/// Read the raw contents of an attribute of a chip below `root`
+///
+/// See `read`.
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub async fn read_async_with_root<P: AsRef<Path>>(
+    root: P,
+    chip: u32,
+    pin: u32,
+    name: &str,
+) -> Result<String> {
+    pwm_file_read(root.as_ref(), chip, pin, name).await
+}
+
+/// Write `value` as-is to an attribute of a chip below `root`
+///
+/// See `write`.
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub async fn write_async_with_root<P: AsRef<Path>>(
+    root: P,
+    chip: u32,
+    pin: u32,
+    name: &str,
+    value: &[u8],
+) -> Result<()> {
+    pwm_file_write(root.as_ref(), chip, pin, name, value).await
+}
+
+/// Read an attribute of a chip below `root` and parse it as a `T`
+///
+/// See `parse`.
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub async fn parse_async_with_root<T: FromStr, P: AsRef<Path>>(
+    root: P,
+    chip: u32,
+    pin: u32,
+    name: &str,
+) -> Result<T> {
+    parse_value(&read_async_with_root(root, chip, pin, name).await?)
+}
@@ -0,0 +1,84 @@
+// Copyright 2016, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The async file operations and timers used by `pwm_async`
+//!
+//! These come from tokio with the `tokio` feature and from async-std with
+//! the `async-std` feature.  Only one should be enabled.  If both are,
+//! e.g. with `--all-features` or when the default features were not turned
+//! off, tokio takes precedence and async-std goes unused; this is not a
+//! compile error so that `--all-features` builds keep working.
+//!
+//! `ExportedPwmAsync::spawn_capture_poller`, `coalescing_writer` and its
+//! `DutySink` spawn tokio tasks, so they only exist with the `tokio`
+//! feature whichever other runtime is enabled.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+
+#[cfg(not(feature = "tokio"))]
+use async_std::io::{prelude::SeekExt, SeekFrom};
+#[cfg(not(feature = "tokio"))]
+use async_std::stream::StreamExt;
+#[cfg(feature = "tokio")]
+use tokio::io::AsyncSeekExt;
+
+#[cfg(not(feature = "tokio"))]
+pub(crate) use async_std::fs::{metadata, read_to_string, File, OpenOptions};
+#[cfg(not(feature = "tokio"))]
+pub(crate) use async_std::io::{ReadExt, WriteExt};
+#[cfg(not(feature = "tokio"))]
+pub(crate) use async_std::task::sleep;
+#[cfg(feature = "tokio")]
+pub(crate) use tokio::fs::{metadata, read_to_string, File, OpenOptions};
+#[cfg(feature = "tokio")]
+pub(crate) use tokio::io::{AsyncReadExt as ReadExt, AsyncWriteExt as WriteExt};
+#[cfg(feature = "tokio")]
+pub(crate) use tokio::time::sleep;
+
+/// Seek back to the start of `file`
+pub(crate) async fn rewind(file: &mut File) -> io::Result<()> {
+    #[cfg(feature = "tokio")]
+    return file.rewind().await.map(|_| ());
+    #[cfg(not(feature = "tokio"))]
+    return file.seek(SeekFrom::Start(0)).await.map(|_| ());
+}
+
+/// The names of the entries of the directory at `path`
+pub(crate) async fn dir_names(path: &Path) -> io::Result<Vec<OsString>> {
+    let mut names = Vec::new();
+    #[cfg(feature = "tokio")]
+    {
+        let mut entries = tokio::fs::read_dir(path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            names.push(entry.file_name());
+        }
+    }
+    #[cfg(not(feature = "tokio"))]
+    {
+        let mut entries = async_std::fs::read_dir(path).await?;
+        while let Some(entry) = entries.next().await {
+            names.push(entry?.file_name());
+        }
+    }
+    Ok(names)
+}
+
+/// Run `future` to completion on the current thread
+#[cfg(test)]
+pub(crate) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    #[cfg(feature = "tokio")]
+    return tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future);
+    #[cfg(not(feature = "tokio"))]
+    return async_std::task::block_on(future);
+}